@@ -1,13 +1,36 @@
 use anyhow::{Context, Result};
-use clap::{error::ErrorKind, Command, CommandFactory, Parser, Subcommand};
+use clap::{
+    error::ErrorKind, ArgMatches, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+};
 use crossbeam::{
     channel::{unbounded, Receiver},
     select,
 };
-use playground::{partition::Partition, Env};
+use playground::{
+    capture::Capture,
+    cgroup, clock, data,
+    fault::{self, Fault},
+    health, logs,
+    partition::Partition,
+    probe::Probe,
+    supervisor::{parse_signal, Instance, Signal},
+    template,
+    trigger::Trigger,
+    user::Credentials,
+    Env,
+};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use std::{collections::BTreeMap, env, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    env,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use tracing::metadata::LevelFilter;
 
 #[derive(Debug, Parser)]
@@ -22,6 +45,8 @@ struct Cli {
 enum Commands {
     Run(Run),
     Cleanup(Cleanup),
+    Status(StatusOpts),
+    Logs(LogsOpts),
 }
 
 #[derive(Debug, Parser)]
@@ -30,9 +55,34 @@ struct Run {
         long = "command",
         short = 'c',
         help = "command to execute. 
-occurances of {index} in command will be replaced with a command autoincrement"
+placeholders in the command are replaced with values for every instance:
+{index}     - command autoincrement
+{ip}        - address of the instance
+{peers}     - comma separated addresses of all instances, on all hosts
+{bridge_ip} - address of the host in the instance subnet
+{host}      - host id
+{total}     - total number of instances, on all hosts
+addresses can be joined with a port, {ip:7000} or {peers:7000}.
+command is split into arguments using shell rules, quotes and escapes are supported:
+-c \"sh -c 'echo a b'\""
     )]
     commands: Vec<String>,
+    #[clap(
+        long = "shell",
+        help = "run every command with /bin/sh -c. allows to use pipes, redirects and other shell features."
+    )]
+    shell: bool,
+    #[clap(
+        long = "name",
+        help = "name of the preceding command, optionally with a port. NAME[:PORT]
+instances of the command get hostnames <name>-0, <name>-1 and so on.
+by default hostname is the name of the namespace, <prefix>-<index>.
+hostnames of all instances are added to /etc/hosts in every namespace.
+hostnames must not be longer than 63 characters.
+port is advertised in SRV records if --dns is enabled.",
+        value_parser = parse_name,
+    )]
+    names: Vec<Name>,
     #[clap(
         long = "count",
         short = 'n',
@@ -62,9 +112,161 @@ EXAMPLES:
     #[clap(
         long = "env",
         short = 'e',
-        help = "environment variables to set for the command. KEY=VALUE"
+        help = "environment variables to set for the command. KEY=VALUE
+variables provided after a command apply only to that command,
+variables provided before the first command apply to all commands.
+values support the same placeholders as commands.
+EXAMPLES:
+-e RUST_LOG=debug -c 'first' -e ROLE=leader -c 'second' -e ROLE=follower -e SEEDS={peers:7000}"
     )]
     env: Vec<EnvValue>,
+    #[clap(
+        long = "no-default-env",
+        help = "do not set PLAY_INDEX, PLAY_IP, PLAY_PEERS, PLAY_BRIDGE_IP, PLAY_HOST and PLAY_TOTAL 
+environment variables for every command."
+    )]
+    no_default_env: bool,
+    #[clap(
+        long = "no-peers-env",
+        help = "do not set PLAY_PEERS, other default variables are still set.
+a single variable is limited to 128KiB, so it doesn't fit addresses of more than about 11k instances."
+    )]
+    no_peers_env: bool,
+    #[clap(
+        long = "dns",
+        help = "run dns server on the host address in the instance subnet ({bridge_ip}).
+it resolves names of instances, and names of commands to addresses of all their instances.
+for commands with a port SRV records are served for _<name>._tcp and _<name>._udp.
+other queries are forwarded to the nameserver of the host."
+    )]
+    dns: bool,
+    #[clap(
+        long = "dns-domain",
+        help = "domain for the names served by dns server.",
+        default_value = "play"
+    )]
+    dns_domain: String,
+    #[clap(
+        long = "ready",
+        help = "readiness probe for the preceding command. instance is ready when probe succeeds.
+tcp:<port>        - port accepts connections on the instance address, or on 127.0.0.1 in its namespace
+http:<port>[path] - GET request returns 200
+file:<path>       - file exists, relative to the working directory
+log:<regex>       - line that matches regex is printed to stdout or stderr
+exec:<command>    - command exits with 0 when run with /bin/sh -c the same way as the instance
+path and command support the same placeholders as commands.",
+        value_parser = Probe::parse,
+    )]
+    ready: Vec<Probe>,
+    #[clap(
+        long = "after",
+        help = "start the preceding command only after all instances of the named command are ready.
+can be provided multiple times. command is named with --name.
+instances on other hosts are probed over the network, so only tcp and http probes can be used for them.
+EXAMPLES:
+-c 'db' --name db --ready tcp:5432 -c 'app' --name app --ready http:8080/health -c 'client' --after db --after app"
+    )]
+    after: Vec<String>,
+    #[clap(
+        long = "ready-timeout",
+        help = "how long to wait for instances of a command to become ready.",
+        default_value = "60s"
+    )]
+    ready_timeout: humantime::Duration,
+    #[clap(
+        long = "health",
+        help = "health probe for the preceding command. supports the same probes as --ready, except log.
+probe is executed every --health-interval after all commands were started.",
+        value_parser = Probe::parse,
+    )]
+    health: Vec<Probe>,
+    #[clap(
+        long = "health-interval",
+        help = "how often to run health probes and report status.",
+        default_value = "5s"
+    )]
+    health_interval: humantime::Duration,
+    #[clap(
+        long = "health-start-period",
+        help = "how long to wait after instance was started or restarted before running its health probe.",
+        default_value = "0s"
+    )]
+    health_start_period: humantime::Duration,
+    #[clap(
+        long = "health-failures",
+        help = "number of consecutive failed health probes before instance is considered unhealthy.",
+        default_value = "3"
+    )]
+    health_failures: usize,
+    #[clap(long = "restart", help = "restart instances that became unhealthy.")]
+    restart: bool,
+    #[clap(
+        long = "cpu",
+        help = "limit every instance of the preceding command to this number of cpus (cpu.max). may be fractional, e.g 0.5.",
+        value_parser = cgroup::parse_cpu
+    )]
+    cpu: Vec<f64>,
+    #[clap(
+        long = "cpuset",
+        help = "pin every instance of the preceding command to the cpus (cpuset.cpus). EXAMPLE: 0-3,6"
+    )]
+    cpuset: Vec<String>,
+    #[clap(
+        long = "memory",
+        help = "limit memory of every instance of the preceding command (memory.max). supports K, M and G suffixes.",
+        value_parser = cgroup::parse_size,
+    )]
+    memory: Vec<u64>,
+    #[clap(
+        long = "io-weight",
+        help = "io weight of every instance of the preceding command (io.weight). from 1 to 10000, default is 100.",
+        value_parser = clap::value_parser!(u16).range(1..=10000),
+    )]
+    io_weight: Vec<u16>,
+    #[clap(
+        long = "user",
+        help = "run every instance of the preceding command as this user. network and other setup is still done as root.
+by default commands run as the user that invoked sudo (SUDO_USER), or as root without sudo."
+    )]
+    user: Vec<String>,
+    #[clap(
+        long = "group",
+        help = "run every instance of the preceding command with this group instead of the primary group of the user."
+    )]
+    group: Vec<String>,
+    #[clap(
+        long = "clock-offset",
+        help = "run every instance of the preceding command in a time namespace with the offset of monotonic and boottime clocks.
+realtime clock is not affected. offset is a duration that may be negative, or random:<max> to choose
+a random offset within -max..max for every instance.
+EXAMPLES:
+    --clock-offset 10s --clock-offset=-1m --clock-offset random:30s",
+        value_parser = clock::Offset::parse,
+        allow_hyphen_values = true,
+    )]
+    clock_offset: Vec<clock::Offset>,
+    #[clap(
+        long = "data",
+        help = "give every instance a private data directory <work_dir>/<namespace>, mounted to this path.
+the path is visible only to the instance, and is created on the host if it doesn't exist.
+EXAMPLES:
+    --data /data"
+    )]
+    data: Option<PathBuf>,
+    #[clap(
+        long = "data-cleanup",
+        help = "what to do with data directories when playground is stopped: keep, delete, or archive into <work_dir>/<namespace>.tar.gz.",
+        default_value = "keep"
+    )]
+    data_cleanup: data::Cleanup,
+    #[clap(
+        long = "data-size",
+        help = "back every data directory with ext4 of this size on a loop device with device-mapper on top.
+required for disk faults. supports K, M and G suffixes.",
+        value_parser = cgroup::parse_size,
+        requires = "data",
+    )]
+    data_size: Option<u64>,
     #[clap(
         long = "cidr",
         default_value = "10.0.0.0/16",
@@ -92,6 +294,51 @@ it remains in the partitioned state for 10s and then gets restored.
         value_parser = Partition::parse,
     )]
     partition: Option<Partition>,
+    #[clap(
+        long = "fault",
+        help = "apply the fault to the instances every interval, and revert it after duration.
+<kind> [args] [target <target>] interval <interval> duration <duration>
+kinds:
+    cpu <cpus>     - lower cpu.max of the instance to the number of cpus
+    memory <size>  - lower memory.high of the instance, it is throttled and reclaimed above it
+    pause          - freeze the instance (cgroup.freeze, or SIGSTOP without cgroups) and resume it after duration
+    kill [signal]  - kill the instance with the signal (SIGKILL by default) and start it again after duration
+    disk-delay <d> - delay every io on the data device of the instance, requires --data-size
+    disk-error     - fail every write on the data device of the instance with EIO, requires --data-size
+    disk-full      - allocate all free space on the data device of the instance, requires --data-size
+    capture [size] - capture packets of the instance to work_dir/namespace.<unix time>.pcap, rotated after size
+targets:
+    0,2,5-7        - instances with the indices
+    random <n>     - n random instances, chosen every time fault is applied
+    bucket <a..b>  - fraction of the instances ordered by index, e.g. 0.0..0.5
+    all            - every instance, the default
+can be provided multiple times.
+EXAMPLES:
+    --fault='cpu 0.1 target random 2 interval 30s duration 10s'
+    --fault='memory 64M target 0-2 interval 1m duration 20s'
+",
+        value_parser = Fault::parse,
+    )]
+    faults: Vec<Fault>,
+    #[clap(
+        long = "trigger",
+        help = "execute the action when a line of output of any instance matches the regex.
+<action> on <regex>
+actions:
+    fail           - stop the playground with an error
+    dump           - write the line and status of all instances to work_dir/prefix.dump.namespace.json,
+                     once for every instance
+    fault <fault>  - apply the fault once, in the same format as --fault but without interval.
+                     applied to the instance that printed the line unless target is provided,
+                     ignored until the previous application is reverted
+can be provided multiple times.
+EXAMPLES:
+    --trigger='fail on panic|data race'
+    --trigger='fault kill duration 10s on became leader'
+",
+        value_parser = Trigger::parse,
+    )]
+    triggers: Vec<Trigger>,
     #[clap(
         long = "no-revert",
         help = "do not revert the changes made to the network configuration."
@@ -105,9 +352,72 @@ it remains in the partitioned state for 10s and then gets restored.
     work_dirs: Vec<PathBuf>,
     #[clap(
         long = "redirect",
-        help = "redirect stdout and stderr to work_dir/namespace.{stdout, stderr} files."
+        help = "redirect stdout and stderr to work_dir/namespace.log. every line is prefixed with timestamp and stream name."
     )]
     redirect: bool,
+    #[clap(
+        long = "log-max-size",
+        help = "rotate redirected log when it exceeds this size. supports K, M and G suffixes.",
+        value_parser = cgroup::parse_size,
+        requires = "redirect",
+    )]
+    log_max_size: Option<u64>,
+    #[clap(
+        long = "log-max-age",
+        help = "rotate redirected log when it was written longer than this duration.",
+        requires = "redirect"
+    )]
+    log_max_age: Option<humantime::Duration>,
+    #[clap(
+        long = "log-keep",
+        help = "number of rotated logs kept as work_dir/namespace.log.N, older are removed.",
+        default_value = "5"
+    )]
+    log_keep: usize,
+    #[clap(
+        long = "log-archive",
+        help = "compress all logs into work_dir/prefix.logs.tar.gz when playground is stopped.",
+        requires = "redirect"
+    )]
+    log_archive: bool,
+    #[clap(
+        long = "log-json",
+        help = "write every line of output of all instances to this file as JSON objects with
+timestamp, host, index, namespace, stream and line fields. works with and without --redirect."
+    )]
+    log_json: Option<PathBuf>,
+    #[clap(
+        long = "net-stats",
+        help = "sample link and qdisc counters of every instance with this interval,
+and write them to work_dir/namespace.net.csv. last sample is taken when playground is stopped.
+EXAMPLES:
+    --net-stats 10s"
+    )]
+    net_stats: Option<humantime::Duration>,
+    #[clap(
+        long = "metrics",
+        help = "serve prometheus metrics on http://127.0.0.1:port/metrics and on the gateway address
+of every bridge. link counters are sampled every 5s, unless --net-stats is set.
+EXAMPLES:
+    --metrics 9100"
+    )]
+    metrics: Option<u16>,
+    #[clap(
+        long = "capture",
+        help = "capture packets with tcpdump from deploy until the playground is stopped.
+<target> [size <size>] [keep <n>] [filter <expression>]
+target is all, bridge, or indices of instances 0,2,5-7. packets of instances are written to
+work_dir/namespace.pcap<n>, packets of bridges to work_dir/bridge.pcap<n>.
+files are rotated after size (100M by default), and the oldest of keep (5 by default) files is overwritten.
+filter is a pcap filter expression, it takes the rest of the spec.
+can be provided multiple times.
+EXAMPLES:
+    --capture all
+    --capture '0,2 size 10M keep 3 filter tcp port 8080'
+    --capture bridge",
+        value_parser = Capture::parse,
+    )]
+    captures: Vec<Capture>,
     #[clap(
         long = "instances-per-bridge",
         help = "number of instances per bridge.",
@@ -146,6 +456,19 @@ it remains in the partitioned state for 10s and then gets restored.
         default_value = ""
     )]
     vxlan_device: String,
+    #[clap(
+        long = "stop-signal",
+        help = "signal sent to every command when playground is stopped. name (TERM, SIGTERM) or number.",
+        default_value = "SIGTERM",
+        value_parser = parse_signal,
+    )]
+    stop_signal: Signal,
+    #[clap(
+        long = "stop-timeout",
+        help = "how long to wait for commands to exit after stop signal before killing them with SIGKILL.",
+        default_value = "10s"
+    )]
+    stop_timeout: humantime::Duration,
 }
 
 #[derive(Debug, Parser)]
@@ -158,6 +481,54 @@ struct Cleanup {
     prefix: String,
 }
 
+#[derive(Debug, Parser)]
+struct StatusOpts {
+    #[clap(
+        long = "prefix",
+        short = 'p',
+        help = "prefix for playground environment."
+    )]
+    prefix: String,
+}
+
+#[derive(Debug, Parser)]
+struct LogsOpts {
+    #[clap(
+        long = "prefix",
+        short = 'p',
+        help = "prefix for playground environment."
+    )]
+    prefix: String,
+    #[clap(
+        long = "index",
+        help = "print logs only of instances with these indices, e.g. 3,5-9.",
+        value_parser = parse_indices,
+    )]
+    index: Option<Indices>,
+    #[clap(
+        long = "follow",
+        short = 'f',
+        help = "keep printing lines as they are written."
+    )]
+    follow: bool,
+    #[clap(long = "grep", help = "print only lines that match the regex.")]
+    grep: Option<regex::Regex>,
+    #[clap(
+        long = "work-dir",
+        short = 'w',
+        help = "directory with logs, used if playground is not running.",
+        default_value = "."
+    )]
+    work_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct Indices(Vec<usize>);
+
+fn parse_indices(s: &str) -> anyhow::Result<Indices> {
+    fault::parse_indices(s).map(Indices)
+}
+
 #[derive(Debug, Clone)]
 struct HostIdentifier {
     id: usize,
@@ -198,6 +569,41 @@ impl FromStr for EnvValue {
     }
 }
 
+#[derive(Debug, Clone)]
+struct Name {
+    name: String,
+    port: Option<u16>,
+}
+
+fn parse_name(s: &str) -> Result<Name, String> {
+    let (name, port) = match s.split_once(':') {
+        Some((name, port)) => (
+            name,
+            Some(
+                port.parse::<u16>()
+                    .map_err(|err| format!("invalid port {}: {}", port, err))?,
+            ),
+        ),
+        None => (s, None),
+    };
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(format!(
+            "name {} must consist of lowercase letters, digits and dashes",
+            name
+        ));
+    }
+    Ok(Name {
+        name: name.to_string(),
+        port,
+    })
+}
+
 fn main() {
     if let Err(e) = tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
@@ -215,13 +621,23 @@ fn main() {
             )
             .exit();
     }
-    match Cli::parse().command {
-        Commands::Run(opts) => run(Cli::command(), &opts),
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    match cli.command {
+        Commands::Run(opts) => run(
+            Cli::command(),
+            &opts,
+            matches
+                .subcommand_matches("run")
+                .expect("run subcommand must be matched"),
+        ),
         Commands::Cleanup(opts) => cleanup(Cli::command(), &opts),
+        Commands::Status(opts) => status(Cli::command(), &opts),
+        Commands::Logs(opts) => logs(Cli::command(), &opts),
     }
 }
 
-fn run(mut cmd: Command, opts: &Run) {
+fn run(mut cmd: Command, opts: &Run, matches: &ArgMatches) {
     if opts.commands.is_empty() {
         cmd.error(
             ErrorKind::InvalidValue,
@@ -231,8 +647,12 @@ fn run(mut cmd: Command, opts: &Run) {
     }
 
     let (rx, tx) = unbounded();
+    // flag interrupts deploy, that doesn't wait on the channel
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
     if let Err(e) = ctrlc::set_handler(move || {
         tracing::info!("received interrupt. wait for program to cleanup");
+        flag.store(true, Ordering::Relaxed);
         _ = rx.send(());
     }) {
         cmd.error(
@@ -250,12 +670,17 @@ fn run(mut cmd: Command, opts: &Run) {
         opts.instances_per_bridge,
         !opts.no_revert,
         opts.redirect,
+        opts.shell,
         opts.vxlan_id,
         opts.vxlan_port,
         opts.vxlan_multicast_group,
         opts.vxlan_device.clone(),
+        opts.stop_signal,
+        opts.stop_timeout.into(),
+        opts.ready_timeout.into(),
     );
-    let err = rune(opts, &mut e, tx);
+    e.enable_interrupt(interrupted);
+    let err = rune(opts, matches, &mut e, tx);
     if let Err(err) = e.clear() {
         tracing::error!("error during cleanup: {:?}", err);
     };
@@ -264,7 +689,7 @@ fn run(mut cmd: Command, opts: &Run) {
     }
 }
 
-fn rune(opts: &Run, e: &mut Env, tx: Receiver<()>) -> Result<()> {
+fn rune(opts: &Run, matches: &ArgMatches, e: &mut Env, tx: Receiver<()>) -> Result<()> {
     let first_tbf = opts.tbf.first().map(|t| t.clone());
     let first_netem = opts.netem.first().map(|n| n.clone());
     let first_count = opts.counts.first().copied().unwrap_or(1);
@@ -295,34 +720,137 @@ fn rune(opts: &Run, e: &mut Env, tx: Receiver<()>) -> Result<()> {
         })
         .scan((), |_, item| item);
 
-    let commands = opts.commands.iter().enumerate().flat_map(|(i, cmd)| {
-        let count = opts.counts.get(i).copied().unwrap_or(first_count);
-        std::iter::repeat(cmd.clone()).take(count)
-    });
-
-    let work_dirs = (0..total).map(|index| {
-        opts.work_dirs
-            .get(index)
-            .map_or_else(|| default_work_dir.clone(), |w| w.clone())
-    });
+    let mut common_env = if opts.no_default_env {
+        BTreeMap::new()
+    } else {
+        template::default_env(!opts.no_peers_env)
+    };
+    let mut groups_env = vec![BTreeMap::new(); opts.commands.len()];
+    for (EnvValue(k, v), group) in opts.env.iter().zip(command_groups(matches, "env")) {
+        match group {
+            Some(group) => groups_env[group].insert(k.clone(), v.clone()),
+            None => common_env.insert(k.clone(), v.clone()),
+        };
+    }
+    let names = per_command(matches, "names", &opts.names, opts.commands.len())?;
+    let ready = per_command(matches, "ready", &opts.ready, opts.commands.len())?;
+    let health = per_command(matches, "health", &opts.health, opts.commands.len())?;
+    let cpu = per_command(matches, "cpu", &opts.cpu, opts.commands.len())?;
+    let cpuset = per_command(matches, "cpuset", &opts.cpuset, opts.commands.len())?;
+    let memory = per_command(matches, "memory", &opts.memory, opts.commands.len())?;
+    let io_weight = per_command(matches, "io_weight", &opts.io_weight, opts.commands.len())?;
+    let user = per_command(matches, "user", &opts.user, opts.commands.len())?;
+    let group = per_command(matches, "group", &opts.group, opts.commands.len())?;
+    let sudo_user = env::var("SUDO_USER").ok();
+    let mut credentials = vec![];
+    for (user, group) in user.into_iter().zip(group) {
+        credentials.push(match (user.or_else(|| sudo_user.clone()), group) {
+            (None, None) => None,
+            (Some(user), None) if user == "root" => None,
+            (user, group) => Some(Credentials::resolve(
+                user.as_deref().unwrap_or("root"),
+                group.as_deref(),
+            )?),
+        });
+    }
+    let clock_offset = per_command(
+        matches,
+        "clock_offset",
+        &opts.clock_offset,
+        opts.commands.len(),
+    )?;
+    let mut after = vec![vec![]; opts.commands.len()];
+    for (name, group) in opts.after.iter().zip(command_groups(matches, "after")) {
+        match group {
+            Some(group) => after[group].push(name.clone()),
+            None => anyhow::bail!("after {:?} must follow the command it applies to", name),
+        }
+    }
 
-    let os_env = opts
-        .env
-        .iter()
-        .map(|EnvValue(k, v)| (k.clone(), v.clone()))
-        .collect::<BTreeMap<_, _>>();
-    let os_envs = std::iter::repeat(os_env).take(total);
+    let mut instances = vec![];
+    for (i, command) in opts.commands.iter().enumerate() {
+        let count = opts.counts.get(i).copied().unwrap_or(first_count);
+        let mut os_env = common_env.clone();
+        os_env.extend(groups_env[i].clone());
+        for j in 0..count {
+            let index = instances.len();
+            instances.push(Instance {
+                command: command.clone(),
+                env: os_env.clone(),
+                work_dir: opts
+                    .work_dirs
+                    .get(index)
+                    .map_or_else(|| default_work_dir.clone(), |w| w.clone()),
+                hostname: names[i].as_ref().map(|name| format!("{}-{}", name.name, j)),
+                group: names[i].as_ref().map(|name| name.name.clone()),
+                port: names[i].as_ref().and_then(|name| name.port),
+                ready: ready[i].clone(),
+                after: after[i].clone(),
+                health: health[i].clone(),
+                limits: cgroup::Limits {
+                    cpu: cpu[i],
+                    cpuset: cpuset[i].clone(),
+                    memory: memory[i],
+                    io_weight: io_weight[i],
+                },
+                clock_offset: clock_offset[i].clone(),
+                user: credentials[i].clone(),
+            });
+        }
+    }
 
+    e.enable_log_rotation(
+        logs::Rotation {
+            max_size: opts.log_max_size,
+            max_age: opts.log_max_age.map(Into::into),
+            keep: opts.log_keep,
+        },
+        opts.log_archive,
+    );
+    if let Some(path) = &opts.log_json {
+        e.enable_json_log(path.clone());
+    }
+    for trigger in &opts.triggers {
+        e.enable_trigger(trigger.clone());
+    }
+    if let Some(interval) = opts.net_stats {
+        e.enable_net_stats(interval.into());
+    }
+    if let Some(port) = opts.metrics {
+        e.enable_metrics(port);
+    }
+    for capture in &opts.captures {
+        e.enable_capture(capture.clone());
+    }
+    if let Some(target) = &opts.data {
+        e.enable_data(data::Config {
+            target: target.clone(),
+            size: opts.data_size,
+            cleanup: opts.data_cleanup,
+        });
+    }
     let since = std::time::Instant::now();
-    e.generate(commands, qdisc, os_envs, work_dirs)?;
+    e.generate(instances.into_iter(), qdisc)?;
     tracing::info!("playground generated in {:?}", since.elapsed());
 
+    if opts.dns {
+        e.enable_dns(opts.dns_domain.clone());
+    }
+    e.enable_health(health::Config {
+        interval: opts.health_interval.into(),
+        start_period: opts.health_start_period.into(),
+        failures: opts.health_failures,
+        restart: opts.restart,
+    });
     let since = std::time::Instant::now();
     e.deploy()?;
     tracing::info!("playground deployed in {:?}", since.elapsed());
     if let Some(partition) = &opts.partition {
         e.enable_partition(partition.clone())?;
     }
+    for fault in &opts.faults {
+        e.enable_fault(fault.clone())?;
+    }
     select! {
         recv(tx) -> _ => {
             tracing::debug!("received interrupt on the channel");
@@ -330,7 +858,8 @@ fn rune(opts: &Run, e: &mut Env, tx: Receiver<()>) -> Result<()> {
         recv(e.errors()) -> err => {
             match err {
                 Ok(err) => {
-                    tracing::error!("error in playground: {:?}", err);
+                    // errors end the run with a failure, e.g. when fail trigger matched
+                    err.context("error in playground")?;
                 }
                 Err(_) => {
                     tracing::info!("playground completed successfully");
@@ -341,6 +870,47 @@ fn rune(opts: &Run, e: &mut Env, tx: Receiver<()>) -> Result<()> {
     Ok(())
 }
 
+// command_groups assigns every value of the argument to the command that precedes it.
+// values that are provided before the first command are not assigned to any command.
+fn command_groups(matches: &ArgMatches, id: &str) -> Vec<Option<usize>> {
+    let commands: Vec<usize> = matches
+        .indices_of("commands")
+        .map(|indices| indices.collect())
+        .unwrap_or_default();
+    matches
+        .indices_of(id)
+        .map(|indices| {
+            indices
+                .map(|index| commands.iter().rposition(|command| *command < index))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// per_command assigns values of the argument to the commands they follow.
+// unlike command_groups every value must follow a command, and only one value is allowed per command.
+fn per_command<T: Clone + std::fmt::Debug>(
+    matches: &ArgMatches,
+    id: &str,
+    values: &[T],
+    commands: usize,
+) -> Result<Vec<Option<T>>> {
+    let mut rst = vec![None; commands];
+    for (value, group) in values.iter().zip(command_groups(matches, id)) {
+        match group {
+            Some(group) if rst[group].is_none() => rst[group] = Some(value.clone()),
+            Some(group) => anyhow::bail!(
+                "{} {:?} is provided more than once for command {}",
+                id,
+                value,
+                group
+            ),
+            None => anyhow::bail!("{} {:?} must follow the command it applies to", id, value),
+        }
+    }
+    Ok(rst)
+}
+
 fn cleanup(mut cmd: Command, opts: &Cleanup) {
     let bridges = {
         match playground::shell::bridge_cleanup(&opts.prefix) {
@@ -366,7 +936,108 @@ fn cleanup(mut cmd: Command, opts: &Cleanup) {
             }
         }
     };
-    tracing::info!(bridges = ?bridges, namespaces = ?namespaces, veth = ?veth, "cleanup completed");
+    let hosts = {
+        match playground::hosts::cleanup(&opts.prefix) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+            }
+        }
+    };
+    let state = {
+        match playground::state::cleanup(&opts.prefix) {
+            Ok(state) => state,
+            Err(err) => {
+                cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+            }
+        }
+    };
+    let cgroups = {
+        match cgroup::cleanup(&opts.prefix) {
+            Ok(cgroups) => cgroups,
+            Err(err) => {
+                cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+            }
+        }
+    };
+    let devices = {
+        match data::cleanup_devices(&opts.prefix) {
+            Ok(devices) => devices,
+            Err(err) => {
+                cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+            }
+        }
+    };
+    tracing::info!(bridges = ?bridges, namespaces = ?namespaces, veth = ?veth, hosts = ?hosts, state = ?state, cgroups = ?cgroups, devices = ?devices, "cleanup completed");
+}
+
+fn status(mut cmd: Command, opts: &StatusOpts) {
+    let status = match health::read_status(&opts.prefix) {
+        Ok(status) => status,
+        Err(err) => {
+            cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+        }
+    };
+    println!(
+        "{:<6} {:<24} {:<24} {:<16} {:<8} {:<10} {:<9} {:<8}",
+        "INDEX", "NAME", "HOSTNAME", "IP", "PID", "STATE", "FAILURES", "RESTARTS"
+    );
+    for instance in status {
+        println!(
+            "{:<6} {:<24} {:<24} {:<16} {:<8} {:<10} {:<9} {:<8}",
+            instance.index,
+            instance.name,
+            instance.hostname,
+            instance.ip.to_string(),
+            instance.pid,
+            instance.state.to_string(),
+            instance.failures,
+            instance.restarts
+        );
+    }
+}
+
+fn logs(mut cmd: Command, opts: &LogsOpts) {
+    let mut locations = match logs::locations(&opts.prefix, &opts.work_dir) {
+        Ok(locations) => locations,
+        Err(err) => {
+            cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+        }
+    };
+    if let Some(Indices(indices)) = &opts.index {
+        locations.retain(|location| indices.contains(&location.index));
+    }
+    if locations.is_empty() {
+        cmd.error(
+            ErrorKind::InvalidValue,
+            format!("no logs found for prefix {}", opts.prefix),
+        )
+        .exit();
+    }
+    let mut tail = logs::Tail::new(locations);
+    loop {
+        let lines = match tail.read() {
+            Ok(lines) => lines,
+            Err(err) => {
+                cmd.error(ErrorKind::Io, format!("{:?}", err)).exit();
+            }
+        };
+        for line in lines {
+            if let Some(grep) = &opts.grep {
+                if !grep.is_match(&line.line) {
+                    continue;
+                }
+            }
+            println!(
+                "[{}] {} {} {}",
+                line.name, line.timestamp, line.stream, line.line
+            );
+        }
+        if !opts.follow {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
 }
 
 fn replace_xxx(prefix: &str) -> String {
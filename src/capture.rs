@@ -0,0 +1,417 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::{cgroup, core, fault, netlink, network, supervisor};
+
+// tcpdump rotates files in units of 1,000,000 bytes
+const SIZE_UNIT: u64 = 1_000_000;
+pub(crate) const DEFAULT_SIZE: u64 = 100 * SIZE_UNIT;
+pub(crate) const DEFAULT_KEEP: usize = 5;
+// packets are truncated to this many bytes, same as tcpdump default
+const SNAPLEN: u32 = 262144;
+const LINKTYPE_ETHERNET: u32 = 1;
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Capture records packets of the targeted instances, or of every bridge on this host, with tcpdump.
+// instance packets are captured on its device in the namespace.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    target: Target,
+    // file is rotated after this many bytes
+    size: u64,
+    // number of files, oldest is overwritten
+    keep: usize,
+    // pcap filter expression
+    filter: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    All,
+    // 0,2,5-7
+    Indices(Vec<usize>),
+    Bridge,
+}
+
+impl Capture {
+    // parse <target> [size <size>] [keep <n>] [filter <expression>]
+    // target is all, bridge or indices of instances. filter takes the rest of the spec.
+    // EXAMPLES:
+    // all
+    // 0,2 size 10M keep 3
+    // bridge filter tcp port 8080
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut splitted = s.split_whitespace();
+        let target = match splitted.next().context("missing capture target")? {
+            "all" => Target::All,
+            "bridge" => Target::Bridge,
+            indices => Target::Indices(fault::parse_indices(indices)?),
+        };
+        let mut capture = Self {
+            target,
+            size: DEFAULT_SIZE,
+            keep: DEFAULT_KEEP,
+            filter: None,
+        };
+        while let Some(token) = splitted.next() {
+            match token {
+                "size" => {
+                    capture.size = cgroup::parse_size(splitted.next().context("missing size")?)?;
+                }
+                "keep" => {
+                    capture.keep = splitted
+                        .next()
+                        .context("missing number of files")?
+                        .parse()
+                        .context("invalid number of files")?;
+                    ensure!(capture.keep > 0, "at least one file must be kept");
+                }
+                "filter" => {
+                    let filter = splitted.by_ref().collect::<Vec<_>>().join(" ");
+                    ensure!(!filter.is_empty(), "missing filter expression");
+                    capture.filter = Some(filter);
+                }
+                _ => bail!("unexpected {}. expected one of size, keep, filter", token),
+            }
+        }
+        Ok(capture)
+    }
+
+    // start captures packets of the targeted instances and bridges on this host.
+    // instances that are not on this host are ignored.
+    pub(crate) fn start(
+        &self,
+        commands: &BTreeMap<usize, supervisor::CommandConfig>,
+        data: &core::Data,
+    ) -> Result<Vec<Process>> {
+        let mut devices = vec![];
+        match &self.target {
+            Target::All => {
+                for command in commands.values() {
+                    devices.push((
+                        Some(network::Namespace {
+                            name: command.name.clone(),
+                        }),
+                        network::guest_device(&command.name),
+                        path(&command.work_dir, &command.name),
+                    ));
+                }
+            }
+            Target::Indices(indices) => {
+                for command in indices.iter().filter_map(|index| commands.get(index)) {
+                    devices.push((
+                        Some(network::Namespace {
+                            name: command.name.clone(),
+                        }),
+                        network::guest_device(&command.name),
+                        path(&command.work_dir, &command.name),
+                    ));
+                }
+            }
+            Target::Bridge => {
+                // bridges are shared by instances, files are written next to logs of the first one
+                if let Some(command) = commands.values().next() {
+                    for bridge in data.bridges.values() {
+                        devices.push((
+                            None,
+                            bridge.name.clone(),
+                            path(&command.work_dir, &bridge.name),
+                        ));
+                    }
+                }
+            }
+        }
+        let mut processes = vec![];
+        for (namespace, device, path) in devices {
+            match Process::start(
+                namespace.as_ref(),
+                &device,
+                &path,
+                self.size,
+                self.keep,
+                self.filter.as_deref(),
+            ) {
+                Ok(process) => processes.push(process),
+                Err(err) => {
+                    // tcpdump that is already running would outlive the playground
+                    for process in processes {
+                        if let Err(err) = process.stop() {
+                            tracing::error!("failed to stop capture: {:?}", err);
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(processes)
+    }
+}
+
+// path is the first file of the capture, tcpdump appends a number of the file to it.
+pub(crate) fn path(work_dir: &Path, name: &str) -> PathBuf {
+    work_dir.join(format!("{}.pcap", name))
+}
+
+// Process reads packets of the device from a packet socket and writes them to tcpdump, that
+// rotates files <path><n>, n is from 0 to keep - 1. socket of an instance is opened in its namespace,
+// but the reader and tcpdump stay in the namespace of the playground, so that they are not killed
+// together with the instance.
+pub(crate) struct Process {
+    device: String,
+    child: Child,
+    stop: Arc<AtomicBool>,
+    reader: JoinHandle<Result<()>>,
+}
+
+impl Process {
+    // start captures packets of the device in the namespace, or on this host if namespace is none.
+    pub(crate) fn start(
+        namespace: Option<&network::Namespace>,
+        device: &str,
+        path: &Path,
+        size: u64,
+        keep: usize,
+        filter: Option<&str>,
+    ) -> Result<Self> {
+        let socket = match namespace {
+            Some(namespace) => netlink::in_namespace(namespace, || open_socket(device)),
+            None => open_socket(device),
+        }
+        .with_context(|| format!("open packet socket on {}", device))?;
+
+        let mut command = Command::new("tcpdump");
+        // packets are read from stdin, filter is applied by tcpdump.
+        // -U flushes every packet, so that files can be inspected while playground is running.
+        // -Z root keeps permissions to write into the working directory.
+        command
+            .args(["-r", "-", "-U", "-Z", "root", "-C"])
+            .arg(size.div_ceil(SIZE_UNIT).to_string())
+            .arg("-W")
+            .arg(keep.to_string())
+            .arg("-w")
+            .arg(path);
+        if let Some(filter) = filter {
+            command.arg(filter);
+        }
+        tracing::debug!("running: {:?}", command);
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("start tcpdump for {}", device))?;
+        let mut stdin = child.stdin.take().expect("stdin must be piped");
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stop = stop.clone();
+            spawn(move || {
+                stdin.write_all(&file_header())?;
+                forward(&socket, &mut stdin, &stop)
+            })
+        };
+        tracing::info!("capturing packets of {} to {:?}", device, path);
+        Ok(Self {
+            device: device.to_string(),
+            child,
+            stop,
+            reader,
+        })
+    }
+
+    // stop closes input of tcpdump, it flushes captured packets and exits.
+    pub(crate) fn stop(mut self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let forwarded = match self.reader.join() {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("packet reader panicked")),
+        };
+        let status = self.child.wait()?;
+        if status.success() {
+            return forwarded.with_context(|| format!("capture packets of {}", self.device));
+        }
+        let mut stderr = String::new();
+        if let Some(mut output) = self.child.stderr.take() {
+            _ = output.read_to_string(&mut stderr);
+        }
+        bail!(
+            "tcpdump for {} exited with {}. stderr: {}",
+            self.device,
+            status,
+            stderr.trim()
+        )
+    }
+}
+
+// open_socket opens a packet socket bound to the device, it receives packets in both directions.
+fn open_socket(device: &str) -> Result<OwnedFd> {
+    let name = CString::new(device)?;
+    // SAFETY: name is a valid nul-terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error()).context("find device");
+    }
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    // SAFETY: socket doesn't take pointers, returned descriptor is owned right away
+    let socket = unsafe {
+        let fd = libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            protocol as i32,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("create socket");
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+    // SAFETY: sockaddr_ll is plain data, zeroed value is valid
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = index as i32;
+    // SAFETY: addr lives until the call returns and its size is passed along
+    let rc = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error()).context("bind socket");
+    }
+    // receive times out, so that reader notices when capture is stopped
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: READ_TIMEOUT.as_micros() as libc::suseconds_t,
+    };
+    // SAFETY: timeout lives until the call returns and its size is passed along
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error()).context("set receive timeout");
+    }
+    Ok(socket)
+}
+
+// forward writes every received packet as a pcap record until stop is set.
+fn forward(socket: &OwnedFd, out: &mut impl Write, stop: &AtomicBool) -> Result<()> {
+    let mut buf = vec![0u8; SNAPLEN as usize];
+    while !stop.load(Ordering::Relaxed) {
+        // MSG_TRUNC returns original length of the packet, even if it didn't fit into buffer
+        // SAFETY: buf is valid for writes of its length
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                _ => return Err(err).context("receive packet"),
+            }
+        }
+        let len = len as usize;
+        let captured = len.min(buf.len());
+        let record = record(SystemTime::now(), &buf[..captured], len);
+        // tcpdump exits after the device was removed together with the namespace
+        out.write_all(&record).context("write packet to tcpdump")?;
+    }
+    Ok(())
+}
+
+// file_header is the header of pcap file with ethernet frames.
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_ne_bytes());
+    header.extend_from_slice(&2u16.to_ne_bytes());
+    header.extend_from_slice(&4u16.to_ne_bytes());
+    // timezone offset and accuracy of timestamps
+    header.extend_from_slice(&0i32.to_ne_bytes());
+    header.extend_from_slice(&0u32.to_ne_bytes());
+    header.extend_from_slice(&SNAPLEN.to_ne_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+    header
+}
+
+// record is a pcap record of the packet, len is the length of the packet before it was truncated.
+fn record(timestamp: SystemTime, packet: &[u8], len: usize) -> Vec<u8> {
+    let since = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + packet.len());
+    record.extend_from_slice(&(since.as_secs() as u32).to_ne_bytes());
+    record.extend_from_slice(&since.subsec_micros().to_ne_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_ne_bytes());
+    record.extend_from_slice(&(len as u32).to_ne_bytes());
+    record.extend_from_slice(packet);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let capture = Capture::parse("all").unwrap();
+        assert_eq!(capture.target, Target::All);
+        assert_eq!(capture.size, DEFAULT_SIZE);
+        assert_eq!(capture.keep, DEFAULT_KEEP);
+        assert_eq!(capture.filter, None);
+
+        let capture = Capture::parse("0,2-3 size 10M keep 3").unwrap();
+        assert_eq!(capture.target, Target::Indices(vec![0, 2, 3]));
+        assert_eq!(capture.size, 10 * 1024 * 1024);
+        assert_eq!(capture.keep, 3);
+
+        let capture = Capture::parse("bridge filter tcp port 8080").unwrap();
+        assert_eq!(capture.target, Target::Bridge);
+        assert_eq!(capture.filter.as_deref(), Some("tcp port 8080"));
+
+        assert!(Capture::parse("").is_err());
+        assert!(Capture::parse("random 2").is_err());
+        assert!(Capture::parse("all keep 0").is_err());
+        assert!(Capture::parse("all filter").is_err());
+        assert!(Capture::parse("all size").is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let header = file_header();
+        assert_eq!(header.len(), 24);
+        assert_eq!(header[..4], 0xa1b2c3d4u32.to_ne_bytes());
+
+        let timestamp = UNIX_EPOCH + Duration::from_micros(5_000_007);
+        let record = record(timestamp, &[1, 2, 3], 100);
+        assert_eq!(record.len(), 19);
+        assert_eq!(record[..4], 5u32.to_ne_bytes());
+        assert_eq!(record[4..8], 7u32.to_ne_bytes());
+        assert_eq!(record[8..12], 3u32.to_ne_bytes());
+        assert_eq!(record[12..16], 100u32.to_ne_bytes());
+        assert_eq!(record[16..], [1, 2, 3]);
+    }
+}
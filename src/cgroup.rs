@@ -0,0 +1,262 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// controllers that are delegated to the cgroup of every instance
+const CONTROLLERS: &[&str] = &["cpu", "cpuset", "memory", "io"];
+// period for cpu.max, quota is computed relative to it
+const CPU_PERIOD: u64 = 100_000;
+// kernel rejects quota below 1ms
+const MIN_CPU_QUOTA: u64 = 1_000;
+
+// Limits are applied to the cgroup of the instance.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    // number of cpus, may be fractional
+    pub cpu: Option<f64>,
+    // list of cpus in the format of cpuset.cpus, e.g. 0-3,6
+    pub cpuset: Option<String>,
+    // memory.max in bytes
+    pub memory: Option<u64>,
+    // io.weight in the range 1..10000
+    pub io_weight: Option<u16>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self == &Limits::default()
+    }
+
+    fn files(&self) -> Vec<(&'static str, String)> {
+        let mut files = vec![];
+        if let Some(cpu) = self.cpu {
+            files.push(("cpu.max", cpu_max(cpu)));
+        }
+        if let Some(cpuset) = &self.cpuset {
+            files.push(("cpuset.cpus", cpuset.clone()));
+        }
+        if let Some(memory) = self.memory {
+            files.push(("memory.max", memory.to_string()));
+        }
+        if let Some(weight) = self.io_weight {
+            files.push(("io.weight", format!("default {}", weight)));
+        }
+        files
+    }
+}
+
+// parse_cpu parses number of cpus, it must be large enough for the minimal quota in the period.
+pub fn parse_cpu(s: &str) -> Result<f64> {
+    let cpu: f64 = s
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid number of cpus {}", s))?;
+    let min = MIN_CPU_QUOTA as f64 / CPU_PERIOD as f64;
+    ensure!(
+        cpu.is_finite() && cpu >= min,
+        "number of cpus must be at least {}, got {}",
+        min,
+        s
+    );
+    Ok(cpu)
+}
+
+pub(crate) fn cpu_max(cpu: f64) -> String {
+    format!("{} {}", (cpu * CPU_PERIOD as f64) as u64, CPU_PERIOD)
+}
+
+// parse_size parses number of bytes with optional K, M, G suffix (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size {}", s))?;
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("size {} is too large", s))
+}
+
+// available is true if unified cgroup hierarchy (v2) is mounted.
+pub(crate) fn available() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+fn parent(prefix: &str) -> PathBuf {
+    PathBuf::from(CGROUP_ROOT).join(format!("playground-{}", prefix))
+}
+
+pub(crate) fn path(prefix: &str, name: &str) -> PathBuf {
+    parent(prefix).join(name)
+}
+
+// setup creates parent cgroup for all instances and delegates controllers to it.
+pub(crate) fn setup(prefix: &str) -> Result<()> {
+    let parent = parent(prefix);
+    fs::create_dir_all(&parent).with_context(|| format!("create {:?}", parent))?;
+    enable_controllers(Path::new(CGROUP_ROOT))?;
+    enable_controllers(&parent)?;
+    Ok(())
+}
+
+fn enable_controllers(dir: &Path) -> Result<()> {
+    let available = fs::read_to_string(dir.join("cgroup.controllers"))
+        .with_context(|| format!("read controllers in {:?}", dir))?;
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            tracing::debug!("controller {} is not available in {:?}", controller, dir);
+            continue;
+        }
+        if let Err(err) = fs::write(
+            dir.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        ) {
+            tracing::warn!(
+                "failed to enable controller {} in {:?}: {:?}",
+                controller,
+                dir,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+// create creates cgroup for the instance and applies limits to it.
+pub(crate) fn create(path: &Path, limits: &Limits) -> Result<()> {
+    match fs::create_dir(path) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+            return Err(err).with_context(|| format!("create {:?}", path));
+        }
+        _ => {}
+    }
+    for (file, value) in limits.files() {
+        write(path, file, &value)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write(path: &Path, file: &str, value: &str) -> Result<()> {
+    let target = path.join(file);
+    ensure!(
+        target.exists(),
+        "{:?} doesn't exist. is controller enabled?",
+        target
+    );
+    fs::write(&target, value).with_context(|| format!("write {} to {:?}", value, target))
+}
+
+// procs opens cgroup.procs, so that the forked process can move itself into the cgroup
+// by writing 0 to it without allocating.
+pub(crate) fn procs(path: &Path) -> Result<File> {
+    let procs = path.join("cgroup.procs");
+    OpenOptions::new()
+        .write(true)
+        .open(&procs)
+        .with_context(|| format!("open {:?}", procs))
+}
+
+pub(crate) fn remove(path: &Path) -> Result<()> {
+    match fs::remove_dir(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+// revert removes cgroups of all instances and the parent cgroup.
+// cgroups can be removed only when there are no processes in them.
+pub(crate) fn revert(prefix: &str) -> Result<()> {
+    let parent = parent(prefix);
+    let entries = match fs::read_dir(&parent) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("read {:?}", parent)),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove(&entry.path())?;
+        }
+    }
+    remove(&parent)
+}
+
+pub fn cleanup(prefix: &str) -> Result<usize> {
+    let entries = match fs::read_dir(CGROUP_ROOT) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).context("read cgroup root"),
+    };
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(parent) = name.strip_prefix("playground-") {
+            if parent.starts_with(prefix) {
+                revert(parent)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("2T").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("18446744073709551615K").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu() {
+        assert_eq!(parse_cpu("0.5").unwrap(), 0.5);
+        assert_eq!(parse_cpu("2").unwrap(), 2.0);
+        assert!(parse_cpu("0").is_err());
+        assert!(parse_cpu("-1").is_err());
+        assert!(parse_cpu("0.001").is_err());
+        assert!(parse_cpu("inf").is_err());
+        assert!(parse_cpu("NaN").is_err());
+    }
+
+    #[test]
+    fn test_limits_files() {
+        let limits = Limits {
+            cpu: Some(0.5),
+            cpuset: Some("0-1".to_string()),
+            memory: Some(1 << 20),
+            io_weight: Some(200),
+        };
+        assert_eq!(
+            limits.files(),
+            vec![
+                ("cpu.max", "50000 100000".to_string()),
+                ("cpuset.cpus", "0-1".to_string()),
+                ("memory.max", "1048576".to_string()),
+                ("io.weight", "default 200".to_string()),
+            ]
+        );
+        assert!(Limits::default().is_empty());
+    }
+}
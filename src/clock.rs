@@ -0,0 +1,118 @@
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use nix::time::{clock_gettime, ClockId};
+use rand::Rng;
+
+// Offset is applied to CLOCK_MONOTONIC and CLOCK_BOOTTIME in the time namespace of the instance.
+// CLOCK_REALTIME can't be changed by time namespaces.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Offset {
+    // offset in nanoseconds, may be negative
+    Fixed(i64),
+    // offset is chosen uniformly from -max..=max nanoseconds for every instance
+    Random(i64),
+}
+
+impl Offset {
+    // parse 10s, -1h, random:30s
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(max) = s.strip_prefix("random:") {
+            return Ok(Offset::Random(nanos(max)?));
+        }
+        match s.strip_prefix('-') {
+            Some(offset) => Ok(Offset::Fixed(-nanos(offset)?)),
+            None => Ok(Offset::Fixed(nanos(s.strip_prefix('+').unwrap_or(s))?)),
+        }
+    }
+
+    // resolve returns offset for an instance. kernel rejects offsets that move the clock before
+    // zero, so negative offsets are limited by the uptime of this host.
+    pub(crate) fn resolve(&self) -> i64 {
+        let offset = match self {
+            Offset::Fixed(offset) => *offset,
+            Offset::Random(max) => rand::thread_rng().gen_range(-max..=*max),
+        };
+        let uptime = match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+            Ok(now) => now.tv_sec(),
+            Err(err) => {
+                tracing::warn!("failed to read monotonic clock: {:?}", err);
+                return offset;
+            }
+        };
+        let clamped = clamp(offset, uptime);
+        if clamped != offset {
+            tracing::warn!(
+                "clock offset {}s is limited to {}s by the uptime of the host",
+                offset / 1_000_000_000,
+                clamped / 1_000_000_000
+            );
+        }
+        clamped
+    }
+}
+
+// clamp limits negative offset to whole seconds of uptime, the clock in the namespace starts from zero.
+fn clamp(offset: i64, uptime_secs: i64) -> i64 {
+    offset.max(-uptime_secs.saturating_mul(1_000_000_000))
+}
+
+fn nanos(s: &str) -> Result<i64> {
+    let duration =
+        humantime::parse_duration(s).with_context(|| format!("invalid clock offset {}", s))?;
+    i64::try_from(duration.as_nanos()).with_context(|| format!("clock offset {} is too large", s))
+}
+
+// timens_offsets renders content for /proc/<pid>/timens_offsets.
+pub(crate) fn timens_offsets(offset: i64) -> String {
+    let secs = offset.div_euclid(1_000_000_000);
+    let nanos = offset.rem_euclid(1_000_000_000);
+    let mut offsets = String::new();
+    for clock in ["monotonic", "boottime"] {
+        _ = writeln!(offsets, "{} {} {}", clock, secs, nanos);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Offset::parse("10s").unwrap(), Offset::Fixed(10_000_000_000));
+        assert_eq!(Offset::parse("+1ms").unwrap(), Offset::Fixed(1_000_000));
+        assert_eq!(
+            Offset::parse("-1h").unwrap(),
+            Offset::Fixed(-3_600_000_000_000)
+        );
+        assert_eq!(
+            Offset::parse("random:1s").unwrap(),
+            Offset::Random(1_000_000_000)
+        );
+        assert!(Offset::parse("random:").is_err());
+        assert!(Offset::parse("ten").is_err());
+
+        let random = Offset::Random(1_000).resolve();
+        assert!((-1_000..=1_000).contains(&random));
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(clamp(-5_000_000_000, 10), -5_000_000_000);
+        assert_eq!(clamp(-15_000_000_000, 10), -10_000_000_000);
+        assert_eq!(clamp(15_000_000_000, 10), 15_000_000_000);
+    }
+
+    #[test]
+    fn test_timens_offsets() {
+        assert_eq!(
+            timens_offsets(1_500_000_000),
+            "monotonic 1 500000000\nboottime 1 500000000\n"
+        );
+        assert_eq!(
+            timens_offsets(-1_500_000_000),
+            "monotonic -2 500000000\nboottime -2 500000000\n"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::{fmt::Display, fs, io, path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{disk, shell, user};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // path where data directory is mounted for every instance
+    pub target: PathBuf,
+    // if set, data directory is backed by a device of this size, that can be used for disk faults
+    pub size: Option<u64>,
+    pub cleanup: Cleanup,
+}
+
+// Mount is a private data directory of the instance, source is bind mounted to target
+// in the mount namespace of the instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    // if set, source is a mountpoint of the size-limited device
+    pub device: Option<disk::Device>,
+}
+
+// Cleanup defines what happens with data directories when playground is stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cleanup {
+    Keep,
+    Delete,
+    // compress into <work_dir>/<namespace>.tar.gz and delete
+    Archive,
+}
+
+impl FromStr for Cleanup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "keep" => Cleanup::Keep,
+            "delete" => Cleanup::Delete,
+            "archive" => Cleanup::Archive,
+            _ => bail!(
+                "unknown cleanup {}. expected one of keep, delete, archive",
+                s
+            ),
+        })
+    }
+}
+
+impl Display for Cleanup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cleanup = match self {
+            Cleanup::Keep => "keep",
+            Cleanup::Delete => "delete",
+            Cleanup::Archive => "archive",
+        };
+        write!(f, "{}", cleanup)
+    }
+}
+
+// prepare creates data directory, and the mount point on the host if it doesn't exist.
+// data directory is owned by the user that instance runs as.
+pub(crate) fn prepare(mount: &Mount, owner: Option<&user::Credentials>) -> Result<()> {
+    fs::create_dir_all(&mount.source).with_context(|| format!("create {:?}", mount.source))?;
+    fs::create_dir_all(&mount.target).with_context(|| format!("create {:?}", mount.target))?;
+    if let Some(device) = &mount.device {
+        device.setup(&mount.source)?;
+    }
+    if let Some(owner) = owner {
+        owner.chown(&mount.source)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn cleanup(mount: &Mount, cleanup: Cleanup) -> Result<()> {
+    let dir = &mount.source;
+    if cleanup == Cleanup::Archive && dir.exists() {
+        let mut archive = dir.as_os_str().to_owned();
+        archive.push(".tar.gz");
+        let archive = PathBuf::from(archive);
+        let parent = dir
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("{:?} has no parent", dir))?;
+        let name = dir
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{:?} has no name", dir))?;
+        shell::archive(parent, &[name.to_string_lossy().to_string()], &archive)?;
+        tracing::info!("archived {:?} into {:?}", dir, archive);
+    }
+    if let Some(device) = &mount.device {
+        device.teardown(dir, cleanup == Cleanup::Keep)?;
+    }
+    if cleanup == Cleanup::Keep {
+        return Ok(());
+    }
+    match fs::remove_dir_all(dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+// cleanup_devices removes data devices and their mounts left by playground with the prefix.
+pub fn cleanup_devices(prefix: &str) -> Result<usize> {
+    disk::cleanup(prefix)
+}
@@ -0,0 +1,222 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use nix::{
+    errno::Errno,
+    mount::{mount, umount, MsFlags},
+    sys::statvfs::statvfs,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::shell;
+
+const SECTOR_SIZE: u64 = 512;
+// file that takes all free space of the filesystem while disk is full
+const FILL_FILE: &str = ".play-fill";
+
+// Device backs data directory of the instance with ext4 on a device-mapper device,
+// that is stacked on a loop device with the image file. faults change the table of the device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub name: String,
+    pub image: PathBuf,
+    pub size: u64,
+}
+
+// Table is a device-mapper target that is used for the whole device.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Table {
+    Linear,
+    // every io is delayed
+    Delay(Duration),
+    // every write fails with EIO, reads succeed
+    ErrorWrites,
+}
+
+impl Table {
+    fn render(&self, sectors: u64, device: &str) -> String {
+        match self {
+            Table::Linear => format!("0 {} linear {} 0", sectors, device),
+            Table::Delay(delay) => {
+                format!("0 {} delay {} 0 {}", sectors, device, delay.as_millis())
+            }
+            // device is never up, and writes are errored while it is down
+            Table::ErrorWrites => format!("0 {} flakey {} 0 0 1 1 error_writes", sectors, device),
+        }
+    }
+}
+
+impl Device {
+    pub(crate) fn new(namespace: &str, source: &Path, size: u64) -> Self {
+        let mut image = source.as_os_str().to_owned();
+        image.push(".img");
+        Self {
+            name: format!("play-{}", namespace),
+            image: PathBuf::from(image),
+            size,
+        }
+    }
+
+    fn path(&self) -> String {
+        format!("/dev/mapper/{}", self.name)
+    }
+
+    fn sectors(&self) -> u64 {
+        self.size / SECTOR_SIZE
+    }
+
+    fn loop_device(&self) -> Result<String> {
+        shell::losetup_find(&self.image)?
+            .into_iter()
+            .next()
+            .with_context(|| format!("{:?} is not attached to a loop device", self.image))
+    }
+
+    // setup creates the device with the new filesystem and mounts it to the mountpoint on the host.
+    pub(crate) fn setup(&self, mountpoint: &Path) -> Result<()> {
+        File::create(&self.image)
+            .and_then(|image| image.set_len(self.size))
+            .with_context(|| format!("create image {:?}", self.image))?;
+        let device = shell::losetup_attach(&self.image)?;
+        shell::dmsetup_create(&self.name, &Table::Linear.render(self.sectors(), &device))?;
+        shell::mkfs_ext4(&self.path())?;
+        mount(
+            Some(self.path().as_str()),
+            mountpoint,
+            Some("ext4"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .with_context(|| format!("mount {} to {:?}", self.path(), mountpoint))?;
+        Ok(())
+    }
+
+    pub(crate) fn set(&self, table: Table) -> Result<()> {
+        shell::dmsetup_reload(
+            &self.name,
+            &table.render(self.sectors(), &self.loop_device()?),
+        )
+    }
+
+    // teardown unmounts and removes the device. image is removed unless it should be kept.
+    pub(crate) fn teardown(&self, mountpoint: &Path, keep_image: bool) -> Result<()> {
+        match umount(mountpoint) {
+            // EINVAL if it is not mounted
+            Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+            Err(err) => return Err(err).with_context(|| format!("umount {:?}", mountpoint)),
+        }
+        if Path::new(&self.path()).exists() {
+            shell::dmsetup_remove(&self.name)?;
+        }
+        if !self.image.exists() {
+            return Ok(());
+        }
+        for device in shell::losetup_find(&self.image)? {
+            shell::losetup_detach(&device)?;
+        }
+        if !keep_image {
+            fs::remove_file(&self.image).with_context(|| format!("remove {:?}", self.image))?;
+        }
+        Ok(())
+    }
+}
+
+// cleanup unmounts and removes devices of instances left by playground that didn't exit cleanly.
+// images are kept, as they may still be needed for investigation.
+pub(crate) fn cleanup(prefix: &str) -> Result<usize> {
+    let entries = match fs::read_dir("/dev/mapper") {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).context("read /dev/mapper"),
+    };
+    let mounts = fs::read_to_string("/proc/self/mounts").context("read mounts")?;
+    let mut count = 0;
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if !name.starts_with(&format!("play-{}", prefix)) {
+            continue;
+        }
+        let path = format!("/dev/mapper/{}", name);
+        for mountpoint in mounts.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next() == Some(path.as_str()))
+                .then(|| fields.next())
+                .flatten()
+        }) {
+            match umount(mountpoint) {
+                Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+                Err(err) => return Err(err).with_context(|| format!("umount {}", mountpoint)),
+            }
+        }
+        let loop_devices = shell::dmsetup_deps(&name)?;
+        shell::dmsetup_remove(&name)?;
+        for device in loop_devices {
+            shell::losetup_detach(&format!("/dev/{}", device))?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn free(dir: &Path) -> Result<(u64, u64)> {
+    let stat = statvfs(dir).with_context(|| format!("statvfs {:?}", dir))?;
+    Ok((
+        stat.blocks_free() as u64 * stat.fragment_size() as u64,
+        stat.fragment_size() as u64,
+    ))
+}
+
+// fill allocates all free space of the filesystem that the directory is on.
+pub(crate) fn fill(dir: &Path) -> Result<()> {
+    let file = dir.join(FILL_FILE);
+    let mut filled = 0;
+    let (mut size, block) = free(dir)?;
+    // allocation also needs space for metadata, so whatever is left is allocated in smaller chunks
+    while size >= block {
+        match shell::fallocate(&file, filled, size) {
+            Ok(()) => {
+                filled += size;
+                size = free(dir)?.0;
+            }
+            Err(_) => size /= 2,
+        }
+    }
+    tracing::debug!("allocated {} bytes in {:?}", filled, file);
+    Ok(())
+}
+
+pub(crate) fn unfill(dir: &Path) -> Result<()> {
+    let file = dir.join(FILL_FILE);
+    match fs::remove_file(&file) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", file))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            Table::Linear.render(2048, "/dev/loop3"),
+            "0 2048 linear /dev/loop3 0"
+        );
+        assert_eq!(
+            Table::Delay(Duration::from_millis(100)).render(2048, "/dev/loop3"),
+            "0 2048 delay /dev/loop3 0 100"
+        );
+        assert_eq!(
+            Table::ErrorWrites.render(2048, "/dev/loop3"),
+            "0 2048 flakey /dev/loop3 0 0 1 1 error_writes"
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread::{spawn, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError};
+
+use crate::hosts;
+
+const TYPE_A: u16 = 1;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+
+const TTL: u32 = 60;
+const HEADER_LEN: usize = 12;
+// how often serving threads check if they should stop
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+// unknown names are forwarded to upstream by this many threads of every server
+const FORWARDERS: usize = 4;
+const PENDING_QUERIES: usize = 64;
+// responses are not larger than this without edns
+const MAX_UDP_LEN: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    name: String,
+    ip: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    port: Option<u16>,
+    members: Vec<Member>,
+}
+
+// Zone answers queries for names of instances and groups in the playground.
+//
+// - A <hostname>[.<domain>] - address of the instance
+// - A <group>[.<domain>] - addresses of all instances in the group
+// - SRV _<group>._tcp[.<domain>] or _<group>._udp[.<domain>] - every instance in the group
+//   with the port of the group
+//
+// there are no ipv6 addresses in the playground, AAAA for known names is answered with empty response.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Zone {
+    domain: String,
+    instances: BTreeMap<String, Ipv4Addr>,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Zone {
+    pub(crate) fn new(domain: &str, entries: &[hosts::Entry]) -> Self {
+        let mut instances = BTreeMap::new();
+        let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+        for entry in entries {
+            for name in &entry.names {
+                instances.insert(name.to_lowercase(), entry.ip);
+            }
+            if let (Some(group), Some(name)) = (&entry.group, entry.names.first()) {
+                let group = groups.entry(group.to_lowercase()).or_insert(Group {
+                    port: entry.port,
+                    members: vec![],
+                });
+                group.members.push(Member {
+                    name: name.to_lowercase(),
+                    ip: entry.ip,
+                });
+            }
+        }
+        Zone {
+            domain: domain.trim_matches('.').to_lowercase(),
+            instances,
+            groups,
+        }
+    }
+
+    fn in_domain(&self, name: &str) -> bool {
+        !self.domain.is_empty()
+            && (name == self.domain
+                || name
+                    .strip_suffix(self.domain.as_str())
+                    .is_some_and(|local| local.ends_with('.')))
+    }
+
+    // local strips domain from the name
+    fn local<'a>(&self, name: &'a str) -> &'a str {
+        if !self.in_domain(name) {
+            return name;
+        }
+        name.strip_suffix(self.domain.as_str())
+            .map(|local| local.trim_end_matches('.'))
+            .unwrap_or(name)
+    }
+
+    fn fqdn(&self, name: &str) -> String {
+        if self.domain.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", name, self.domain)
+        }
+    }
+
+    fn lookup(&self, name: &str, qtype: u16) -> Option<(Vec<Record>, Vec<Record>)> {
+        let local = self.local(name);
+        if let Some(ip) = self.instances.get(local) {
+            return Some(match qtype {
+                TYPE_A => (vec![Record::a(name, *ip)], vec![]),
+                _ => (vec![], vec![]),
+            });
+        }
+        if let Some(group) = self.groups.get(local) {
+            return Some(match qtype {
+                TYPE_A => (
+                    group
+                        .members
+                        .iter()
+                        .map(|m| Record::a(name, m.ip))
+                        .collect(),
+                    vec![],
+                ),
+                _ => (vec![], vec![]),
+            });
+        }
+        let mut labels = local.splitn(3, '.');
+        let (service, proto) = (labels.next()?, labels.next()?);
+        if labels.next().is_some() || !(proto == "_tcp" || proto == "_udp") {
+            return None;
+        }
+        let group = self.groups.get(service.strip_prefix('_')?)?;
+        match (qtype, group.port) {
+            (TYPE_SRV, Some(port)) => Some((
+                group
+                    .members
+                    .iter()
+                    .map(|m| Record::srv(name, port, &self.fqdn(&m.name)))
+                    .collect(),
+                group
+                    .members
+                    .iter()
+                    .map(|m| Record::a(&self.fqdn(&m.name), m.ip))
+                    .collect(),
+            )),
+            _ => Some((vec![], vec![])),
+        }
+    }
+
+    // answer returns None if query should be forwarded to upstream server.
+    pub(crate) fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let question = match Question::parse(query) {
+            Ok(question) => question,
+            Err(err) => {
+                tracing::debug!("invalid dns query: {:?}", err);
+                return (query.len() >= HEADER_LEN).then(|| error(query, RCODE_FORMERR));
+            }
+        };
+        let opcode = (u16::from_be_bytes([query[2], query[3]]) >> 11) & 0xf;
+        if opcode != 0 || question.qclass != CLASS_IN {
+            return Some(error(query, RCODE_NOTIMP));
+        }
+        match self.lookup(&question.name, question.qtype) {
+            Some((answers, additional)) => Some(response(
+                query,
+                &question,
+                RCODE_NOERROR,
+                &answers,
+                &additional,
+            )),
+            None if self.in_domain(&question.name) => {
+                Some(response(query, &question, RCODE_NXDOMAIN, &[], &[]))
+            }
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // end of the question section in the query
+    end: usize,
+}
+
+impl Question {
+    fn parse(query: &[u8]) -> Result<Self> {
+        ensure!(query.len() >= HEADER_LEN, "query is too short");
+        let qdcount = u16::from_be_bytes([query[4], query[5]]);
+        ensure!(qdcount == 1, "expected single question, got {}", qdcount);
+        let mut labels = vec![];
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *query.get(pos).context("truncated name")? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            if len & 0xc0 != 0 {
+                bail!("compressed names are not supported in questions");
+            }
+            let label = query.get(pos..pos + len).context("truncated label")?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += len;
+        }
+        let tail = query.get(pos..pos + 4).context("truncated question")?;
+        Ok(Question {
+            name: labels.join("."),
+            qtype: u16::from_be_bytes([tail[0], tail[1]]),
+            qclass: u16::from_be_bytes([tail[2], tail[3]]),
+            end: pos + 4,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Record {
+    name: String,
+    rtype: u16,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn a(name: &str, ip: Ipv4Addr) -> Self {
+        Record {
+            name: name.to_string(),
+            rtype: TYPE_A,
+            data: ip.octets().to_vec(),
+        }
+    }
+
+    fn srv(name: &str, port: u16, target: &str) -> Self {
+        let mut data = vec![];
+        data.extend_from_slice(&0u16.to_be_bytes()); // priority
+        data.extend_from_slice(&0u16.to_be_bytes()); // weight
+        data.extend_from_slice(&port.to_be_bytes());
+        encode_name(&mut data, target);
+        Record {
+            name: name.to_string(),
+            rtype: TYPE_SRV,
+            data,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_name(buf, &self.name);
+        buf.extend_from_slice(&self.rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&TTL.to_be_bytes());
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn header(query: &[u8], rcode: u16, counts: [u16; 4]) -> Vec<u8> {
+    // QR and AA are set, RD is copied from the query, RA is not set as we forward only unknown names
+    let flags = 0x8000 | 0x0400 | (u16::from_be_bytes([query[2], query[3]]) & 0x0100) | rcode;
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&query[0..2]);
+    buf.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        buf.extend_from_slice(&count.to_be_bytes());
+    }
+    buf
+}
+
+fn error(query: &[u8], rcode: u16) -> Vec<u8> {
+    header(query, rcode, [0, 0, 0, 0])
+}
+
+// response is limited to 512 bytes of udp message. records that don't fit are omitted.
+// TC is not set, as zone is not served over tcp and resolvers would fail on retry,
+// instead of using the answers that fit.
+fn response(
+    query: &[u8],
+    question: &Question,
+    rcode: u16,
+    answers: &[Record],
+    additional: &[Record],
+) -> Vec<u8> {
+    let mut records = vec![];
+    let mut counts = [0u16; 2];
+    for (i, record) in answers.iter().chain(additional).enumerate() {
+        let mut encoded = vec![];
+        record.encode(&mut encoded);
+        if question.end + records.len() + encoded.len() > MAX_UDP_LEN {
+            break;
+        }
+        records.extend_from_slice(&encoded);
+        counts[(i >= answers.len()) as usize] += 1;
+    }
+    let mut buf = header(query, rcode, [1, counts[0], 0, counts[1]]);
+    buf.extend_from_slice(&query[HEADER_LEN..question.end]);
+    buf.extend_from_slice(&records);
+    buf
+}
+
+// upstream returns first nameserver from the host resolv.conf
+fn upstream() -> Option<SocketAddr> {
+    let resolv = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<std::net::IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+}
+
+fn forward(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind: SocketAddr = match upstream {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    socket.send_to(query, upstream)?;
+    let mut buf = [0u8; 4096];
+    let (n, _) = socket.recv_from(&mut buf)?;
+    Ok(buf[..n].to_vec())
+}
+
+fn serve(socket: UdpSocket, zone: Arc<Zone>, stop: Receiver<()>) {
+    let upstream = upstream();
+    let (queries, pending) = bounded::<(Vec<u8>, SocketAddr)>(PENDING_QUERIES);
+    std::thread::scope(|scope| {
+        // forwarders exit when queries are dropped on return from serve
+        if let Some(upstream) = upstream {
+            for _ in 0..FORWARDERS {
+                let pending = pending.clone();
+                let socket = &socket;
+                scope.spawn(move || {
+                    for (query, peer) in pending {
+                        match forward(upstream, &query) {
+                            Ok(response) => {
+                                _ = socket.send_to(&response, peer);
+                            }
+                            Err(err) => {
+                                tracing::debug!(
+                                    "failed to forward dns query to {}: {:?}",
+                                    upstream,
+                                    err
+                                );
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            match stop.try_recv() {
+                Err(TryRecvError::Empty) => {}
+                _ => return,
+            }
+            let (n, peer) = match socket.recv_from(&mut buf) {
+                Ok(rst) => rst,
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    tracing::error!("dns server failed to read: {:?}", err);
+                    return;
+                }
+            };
+            let query = &buf[..n];
+            let response = match zone.answer(query) {
+                Some(response) => response,
+                None if upstream.is_some() => {
+                    // query is dropped if upstream is too slow, client retries it
+                    if queries.try_send((query.to_vec(), peer)).is_err() {
+                        tracing::debug!(
+                            "too many pending dns queries, dropped query from {}",
+                            peer
+                        );
+                    }
+                    continue;
+                }
+                None if query.len() >= HEADER_LEN => error(query, RCODE_NXDOMAIN),
+                None => continue,
+            };
+            if let Err(err) = socket.send_to(&response, peer) {
+                tracing::debug!("failed to send dns response to {}: {:?}", peer, err);
+            }
+        }
+    });
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handlers: Vec<JoinHandle<()>>,
+}
+
+impl Background {
+    // spawn serves zone on port 53 of every address.
+    pub(crate) fn spawn(zone: Zone, addrs: impl Iterator<Item = Ipv4Addr>) -> Result<Self> {
+        let zone = Arc::new(zone);
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut handlers = vec![];
+        for addr in addrs {
+            let socket = UdpSocket::bind((addr, 53))
+                .with_context(|| format!("bind dns server to {}:53", addr))?;
+            socket.set_read_timeout(Some(READ_TIMEOUT))?;
+            let zone = zone.clone();
+            let receiver = receiver.clone();
+            handlers.push(spawn(move || serve(socket, zone, receiver)));
+        }
+        Ok(Self { sender, handlers })
+    }
+
+    pub(crate) fn stop(self) {
+        // dropping sender disconnects the channel for every serving thread
+        drop(self.sender);
+        for handler in self.handlers {
+            _ = handler.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_AAAA: u16 = 28;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(&mut buf, name);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    fn test_zone() -> Zone {
+        let entries = vec![
+            hosts::Entry {
+                ip: "10.0.0.2".parse().unwrap(),
+                names: vec!["db-0".to_string(), "p1-0".to_string()],
+                group: Some("db".to_string()),
+                port: Some(5432),
+            },
+            hosts::Entry {
+                ip: "10.0.0.3".parse().unwrap(),
+                names: vec!["db-1".to_string(), "p1-1".to_string()],
+                group: Some("db".to_string()),
+                port: Some(5432),
+            },
+            hosts::Entry {
+                ip: "10.0.0.4".parse().unwrap(),
+                names: vec!["p1-2".to_string()],
+                group: None,
+                port: None,
+            },
+        ];
+        Zone::new("play", &entries)
+    }
+
+    fn counts(response: &[u8]) -> (u16, u16, u16) {
+        (
+            u16::from_be_bytes([response[2], response[3]]) & 0xf,
+            u16::from_be_bytes([response[6], response[7]]),
+            u16::from_be_bytes([response[10], response[11]]),
+        )
+    }
+
+    #[test]
+    fn test_answer_a() {
+        let zone = test_zone();
+        let response = zone.answer(&query("db-1.play", TYPE_A)).unwrap();
+        assert_eq!(&response[0..2], &[0x12, 0x34]);
+        assert_eq!(counts(&response), (RCODE_NOERROR, 1, 0));
+        assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 3]);
+
+        let response = zone.answer(&query("p1-2", TYPE_A)).unwrap();
+        assert_eq!(counts(&response), (RCODE_NOERROR, 1, 0));
+
+        let response = zone.answer(&query("db", TYPE_A)).unwrap();
+        assert_eq!(counts(&response), (RCODE_NOERROR, 2, 0));
+
+        let response = zone.answer(&query("db-0", TYPE_AAAA)).unwrap();
+        assert_eq!(counts(&response), (RCODE_NOERROR, 0, 0));
+    }
+
+    #[test]
+    fn test_answer_srv() {
+        let zone = test_zone();
+        let response = zone.answer(&query("_db._tcp.play", TYPE_SRV)).unwrap();
+        assert_eq!(counts(&response), (RCODE_NOERROR, 2, 2));
+    }
+
+    #[test]
+    fn test_answer_unknown() {
+        let zone = test_zone();
+        let response = zone.answer(&query("web-0.play", TYPE_A)).unwrap();
+        assert_eq!(counts(&response), (RCODE_NXDOMAIN, 0, 0));
+        assert!(zone.answer(&query("example.com", TYPE_A)).is_none());
+    }
+
+    #[test]
+    fn test_answer_truncated() {
+        let entries: Vec<hosts::Entry> = (0..100)
+            .map(|i| hosts::Entry {
+                ip: Ipv4Addr::new(10, 0, 0, i as u8 + 2),
+                names: vec![format!("db-{}", i)],
+                group: Some("db".to_string()),
+                port: Some(5432),
+            })
+            .collect();
+        let zone = Zone::new("play", &entries);
+        let response = zone.answer(&query("db", TYPE_A)).unwrap();
+        assert!(response.len() <= MAX_UDP_LEN);
+        assert_eq!(response[2] & 0x02, 0);
+        let (_, answers, _) = counts(&response);
+        assert!(answers > 0 && answers < 100);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    ffi::{CStr, CString, OsString},
+    fs::File,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use nix::{
+    fcntl::{open, OFlag},
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sched::{setns, unshare, CloneFlags},
+    sys::stat::Mode,
+    unistd::{setgid, setgroups, sethostname, setuid, write, Gid, Uid},
+};
+
+use crate::{clock, netlink, network, user};
+
+// not exposed by nix, see linux/sched.h
+const CLONE_NEWTIME: i32 = 0x80;
+const TIMENS_OFFSETS: &[u8] = b"/proc/self/timens_offsets\0";
+
+// Hook prepares forked process before exec.
+// it replicates what `ip netns exec` does, but without spawning additional process:
+// - enters network namespace
+// - unshares mount namespace and remounts /sys, so that it describes the network namespace
+// - bind mounts files from /etc/netns/<name>/ over the files in /etc
+//
+// additionally it unshares uts namespace to set a hostname for the instance,
+// moves the process into the cgroup of the instance if it is provided,
+// and unshares time namespace if clock offset is provided. process enters time namespace on exec.
+// privileges are dropped last, if user is provided, as everything else requires root.
+//
+// everything that requires allocation is done in new, as run is executed after fork.
+pub(crate) struct Hook {
+    netns: File,
+    hostname: OsString,
+    sysfs: CString,
+    binds: Vec<(CString, CString)>,
+    // cgroup.procs of the instance cgroup
+    cgroup: Option<File>,
+    // content for timens_offsets
+    timens: Option<Vec<u8>>,
+    user: Option<(Uid, Gid, Vec<Gid>)>,
+}
+
+impl Hook {
+    pub(crate) fn new(namespace: &network::Namespace, hostname: &str) -> Result<Self> {
+        let path = netlink::ns_path(namespace);
+        let netns = File::open(&path).with_context(|| format!("open namespace {}", path))?;
+        Ok(Self {
+            netns,
+            hostname: hostname.into(),
+            sysfs: CString::new(namespace.name.as_str())?,
+            binds: etc_binds(&Path::new("/etc/netns").join(&namespace.name))?,
+            cgroup: None,
+            timens: None,
+            user: None,
+        })
+    }
+
+    pub(crate) fn with_cgroup(mut self, procs: File) -> Self {
+        self.cgroup = Some(procs);
+        self
+    }
+
+    // with_bind bind mounts source over target, after files from /etc/netns/<name>/.
+    pub(crate) fn with_bind(mut self, source: &Path, target: &Path) -> Result<Self> {
+        self.binds.push((
+            CString::new(source.as_os_str().as_bytes())?,
+            CString::new(target.as_os_str().as_bytes())?,
+        ));
+        Ok(self)
+    }
+
+    pub(crate) fn with_clock_offset(mut self, offset: i64) -> Self {
+        self.timens = Some(clock::timens_offsets(offset).into_bytes());
+        self
+    }
+
+    pub(crate) fn with_user(mut self, credentials: &user::Credentials) -> Self {
+        self.user = Some((
+            Uid::from_raw(credentials.uid),
+            Gid::from_raw(credentials.gid),
+            credentials
+                .groups
+                .iter()
+                .map(|gid| Gid::from_raw(*gid))
+                .collect(),
+        ));
+        self
+    }
+
+    pub(crate) fn run(&self) -> io::Result<()> {
+        if let Some(procs) = &self.cgroup {
+            // 0 stands for the process that writes to the file
+            write(procs, b"0")?;
+        }
+        setns(&self.netns, CloneFlags::CLONE_NEWNET)?;
+        let mut flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUTS;
+        if self.timens.is_some() {
+            flags |= CloneFlags::from_bits_retain(CLONE_NEWTIME);
+        }
+        unshare(flags)?;
+        if let Some(offsets) = &self.timens {
+            // offsets can be written only until the first process enters the namespace
+            let path = CStr::from_bytes_with_nul(TIMENS_OFFSETS).expect("valid path");
+            let fd = open(path, OFlag::O_WRONLY, Mode::empty())?;
+            // SAFETY: fd was just opened and is not owned by anything else
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            write(&fd, offsets)?;
+        }
+        sethostname(&self.hostname)?;
+        // don't let any mounts propagate back to the parent
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            None::<&str>,
+        )?;
+        // failure is not important, sysfs may be not mounted at all
+        let _ = umount2("/sys", MntFlags::MNT_DETACH);
+        mount(
+            Some(self.sysfs.as_c_str()),
+            "/sys",
+            Some("sysfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )?;
+        for (source, target) in &self.binds {
+            mount(
+                Some(source.as_c_str()),
+                target.as_c_str(),
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )?;
+        }
+        if let Some((uid, gid, groups)) = &self.user {
+            setgroups(groups)?;
+            setgid(*gid)?;
+            setuid(*uid)?;
+        }
+        Ok(())
+    }
+}
+
+fn etc_binds(dir: &Path) -> Result<Vec<(CString, CString)>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("read {:?}", dir)),
+    };
+    let mut binds = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let target = Path::new("/etc").join(entry.file_name());
+        binds.push((
+            CString::new(entry.path().as_os_str().as_bytes())?,
+            CString::new(target.as_os_str().as_bytes())?,
+        ));
+    }
+    Ok(binds)
+}
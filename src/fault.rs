@@ -0,0 +1,725 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    thread::{spawn, JoinHandle},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use crossbeam::{channel::Sender, select};
+use humantime::Duration;
+use nix::{
+    errno::Errno,
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use rand::seq::SliceRandom;
+
+use crate::{capture, cgroup, data, disk, metrics, network, supervisor};
+
+// Fault is applied to the targeted instances every interval, and reverted after duration.
+// fault without interval is applied once, when it is triggered.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    kind: Kind,
+    target: Target,
+    interval: Option<Duration>,
+    duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum Kind {
+    // lower cpu.max to the number of cpus
+    Cpu(f64),
+    // lower memory.high to the number of bytes, instance is throttled and reclaimed above it
+    Memory(u64),
+    // freeze all processes of the instance, e.g. to simulate gc pause or vm stall
+    Pause,
+    // kill the instance with the signal, and start it again after duration
+    Kill(supervisor::Signal),
+    // delay every io on the data device of the instance
+    DiskDelay(Duration),
+    // fail every write on the data device of the instance with EIO
+    DiskError,
+    // allocate all free space of the data device of the instance
+    DiskFull,
+    // capture packets of the instance for duration, files are rotated after the number of bytes
+    Capture(u64),
+}
+
+// Target selects instances that fault is applied to, out of the instances on this host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    All,
+    // 0,2,5-7
+    Indices(Vec<usize>),
+    // random 3, new instances are chosen every time fault is applied
+    Random(usize),
+    // bucket 0.5..1.0, fraction of instances ordered by index
+    Bucket(f64, f64),
+    // instance that triggered the fault, default for triggered faults
+    Matched,
+}
+
+impl Fault {
+    // parse <kind> [args] [target <target>] interval 30s duration 10s
+    // EXAMPLES:
+    // cpu 0.1 target 0,2 interval 30s duration 10s
+    // memory 64M target random 2 interval 1m duration 20s
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::parse_spec(s, false)
+    }
+
+    // parse_triggered parses <kind> [args] [target <target>] duration 10s
+    // fault is applied once when it is triggered. without target it is applied to the instance
+    // that triggered it.
+    pub fn parse_triggered(s: &str) -> Result<Self> {
+        Self::parse_spec(s, true)
+    }
+
+    fn parse_spec(s: &str, triggered: bool) -> Result<Self> {
+        tracing::debug!("parsing fault: {}", s);
+        let mut splitted = s.split_whitespace();
+        let kind = splitted.next().context("missing fault kind")?;
+        let mut args = vec![];
+        let mut next = None;
+        for token in splitted.by_ref() {
+            if token == "target" || token == "interval" || token == "duration" {
+                next = Some(token);
+                break;
+            }
+            args.push(token);
+        }
+        let kind = Kind::parse(kind, &args)?;
+
+        let mut target = if triggered {
+            Target::Matched
+        } else {
+            Target::All
+        };
+        if next == Some("target") {
+            target = Target::parse(&mut splitted)?;
+            next = splitted.next();
+        }
+        let interval = match next {
+            Some("interval") if !triggered => {
+                let interval = splitted.next().context("missing interval")?.parse()?;
+                next = splitted.next();
+                Some(interval)
+            }
+            Some("interval") => bail!("triggered fault can't have interval"),
+            _ if triggered => None,
+            _ => bail!("missing interval"),
+        };
+        let duration = match next {
+            Some("duration") => splitted.next().context("missing duration")?.parse()?,
+            _ => bail!("missing duration"),
+        };
+        if let Some(token) = splitted.next() {
+            bail!("unexpected {} after duration", token);
+        }
+        Ok(Self {
+            kind,
+            target,
+            interval,
+            duration,
+        })
+    }
+}
+
+impl Kind {
+    fn parse(kind: &str, args: &[&str]) -> Result<Self> {
+        let arg = |name: &str| -> Result<&str> {
+            ensure!(
+                args.len() == 1,
+                "{} fault expects single argument: {}, got {:?}",
+                kind,
+                name,
+                args
+            );
+            Ok(args[0])
+        };
+        let none = || -> Result<()> {
+            ensure!(
+                args.is_empty(),
+                "{} fault expects no arguments, got {:?}",
+                kind,
+                args
+            );
+            Ok(())
+        };
+        Ok(match kind {
+            "cpu" => Kind::Cpu(cgroup::parse_cpu(arg("cpus")?)?),
+            "memory" => Kind::Memory(cgroup::parse_size(arg("size")?)?),
+            "pause" => {
+                none()?;
+                Kind::Pause
+            }
+            "kill" if args.is_empty() => Kind::Kill(supervisor::Signal::SIGKILL),
+            "kill" => Kind::Kill(supervisor::parse_signal(arg("signal")?)?),
+            "disk-delay" => Kind::DiskDelay(arg("delay")?.parse()?),
+            "disk-error" => {
+                none()?;
+                Kind::DiskError
+            }
+            "disk-full" => {
+                none()?;
+                Kind::DiskFull
+            }
+            "capture" if args.is_empty() => Kind::Capture(capture::DEFAULT_SIZE),
+            "capture" => Kind::Capture(cgroup::parse_size(arg("size")?)?),
+            _ => bail!(
+                "unknown fault {}. expected one of cpu, memory, pause, kill, disk-delay, disk-error, disk-full, capture",
+                kind
+            ),
+        })
+    }
+
+    // name is the same as in the fault spec.
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Cpu(_) => "cpu",
+            Kind::Memory(_) => "memory",
+            Kind::Pause => "pause",
+            Kind::Kill(_) => "kill",
+            Kind::DiskDelay(_) => "disk-delay",
+            Kind::DiskError => "disk-error",
+            Kind::DiskFull => "disk-full",
+            Kind::Capture(_) => "capture",
+        }
+    }
+
+    fn action(&self, processes: Processes) -> Box<dyn Action> {
+        match self {
+            Kind::Cpu(cpu) => Box::new(CpuThrottle { cpu: *cpu }),
+            Kind::Memory(high) => Box::new(MemoryPressure { high: *high }),
+            Kind::Pause => Box::new(Pause {
+                tasks: processes.tasks,
+            }),
+            Kind::Kill(signal) => Box::new(Kill {
+                signal: *signal,
+                processes,
+                stopped: BTreeSet::new(),
+            }),
+            Kind::DiskDelay(delay) => Box::new(DiskTable {
+                table: disk::Table::Delay((*delay).into()),
+            }),
+            Kind::DiskError => Box::new(DiskTable {
+                table: disk::Table::ErrorWrites,
+            }),
+            Kind::DiskFull => Box::new(DiskFull),
+            Kind::Capture(size) => Box::new(Capture {
+                size: *size,
+                processes: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+// parse_indices parses comma separated indices and inclusive ranges, e.g. 0,2,5-7.
+pub fn parse_indices(s: &str) -> Result<Vec<usize>> {
+    let mut rst = vec![];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let from: usize = from.parse().context("invalid index")?;
+                let to: usize = to.parse().context("invalid index")?;
+                ensure!(from <= to, "invalid range {}", part);
+                rst.extend(from..=to);
+            }
+            None => rst.push(part.parse().context("invalid index")?),
+        }
+    }
+    Ok(rst)
+}
+
+impl Target {
+    fn parse<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Self> {
+        Ok(match tokens.next().context("missing target")? {
+            "all" => Target::All,
+            "random" => Target::Random(
+                tokens
+                    .next()
+                    .context("missing number of random instances")?
+                    .parse()
+                    .context("invalid number of random instances")?,
+            ),
+            "bucket" => {
+                let bucket = tokens.next().context("missing bucket")?;
+                let (from, to) = bucket.split_once("..").with_context(|| {
+                    format!("bucket must be in the form of 0.0..0.5, got {}", bucket)
+                })?;
+                let (from, to): (f64, f64) = (
+                    from.parse().context("invalid bucket start")?,
+                    to.parse().context("invalid bucket end")?,
+                );
+                ensure!(
+                    (0.0..=1.0).contains(&from) && (0.0..=1.0).contains(&to) && from < to,
+                    "bucket must be within 0.0..1.0, got {}",
+                    bucket
+                );
+                Target::Bucket(from, to)
+            }
+            indices => Target::Indices(parse_indices(indices)?),
+        })
+    }
+
+    // select chooses targeted instances out of the sorted list.
+    pub(crate) fn select(&self, instances: &[usize]) -> Vec<usize> {
+        match self {
+            Target::All => instances.to_vec(),
+            Target::Indices(indices) => instances
+                .iter()
+                .filter(|index| indices.contains(index))
+                .copied()
+                .collect(),
+            Target::Random(n) => {
+                let mut chosen: Vec<usize> = instances
+                    .choose_multiple(&mut rand::thread_rng(), *n)
+                    .copied()
+                    .collect();
+                chosen.sort();
+                chosen
+            }
+            // triggered fault is created only for the matched instance
+            Target::Matched => instances.to_vec(),
+            Target::Bucket(from, to) => {
+                let len = instances.len() as f64;
+                let start = (from * len).floor() as usize;
+                let end = ((to * len).ceil() as usize).min(instances.len());
+                instances[start..end].to_vec()
+            }
+        }
+    }
+}
+
+// Action is a fault that is applied to a single instance, and later reverted.
+pub(crate) trait Action: Send {
+    fn apply(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()>;
+    fn revert(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()>;
+
+    // abort is called instead of revert when playground is stopped.
+    fn abort(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        self.revert(index, command)
+    }
+}
+
+// Processes gives actions access to the running commands.
+#[derive(Clone)]
+pub(crate) struct Processes {
+    pub(crate) tasks: Arc<Mutex<BTreeMap<usize, supervisor::Execution>>>,
+    pub(crate) errors: Sender<Result<()>>,
+    pub(crate) stop_timeout: std::time::Duration,
+    pub(crate) metrics: Arc<metrics::Registry>,
+}
+
+fn cgroup_path(command: &supervisor::CommandConfig) -> Result<&std::path::Path> {
+    command
+        .cgroup
+        .as_deref()
+        .with_context(|| format!("command {} doesn't run in a cgroup", command.name))
+}
+
+struct CpuThrottle {
+    cpu: f64,
+}
+
+impl Action for CpuThrottle {
+    fn apply(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        cgroup::write(cgroup_path(command)?, "cpu.max", &cgroup::cpu_max(self.cpu))
+    }
+
+    fn revert(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        let max = match command.limits.cpu {
+            Some(cpu) => cgroup::cpu_max(cpu),
+            None => "max".to_string(),
+        };
+        cgroup::write(cgroup_path(command)?, "cpu.max", &max)
+    }
+}
+
+struct MemoryPressure {
+    high: u64,
+}
+
+impl Action for MemoryPressure {
+    fn apply(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        cgroup::write(cgroup_path(command)?, "memory.high", &self.high.to_string())
+    }
+
+    fn revert(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        cgroup::write(cgroup_path(command)?, "memory.high", "max")
+    }
+}
+
+// Pause freezes the cgroup of the instance. if instance doesn't run in a cgroup,
+// process group of the instance is stopped with SIGSTOP instead.
+struct Pause {
+    tasks: Arc<Mutex<BTreeMap<usize, supervisor::Execution>>>,
+}
+
+impl Pause {
+    fn signal(&self, index: usize, signal: Signal) -> Result<()> {
+        let tasks = self.tasks.lock().unwrap();
+        let execution = match tasks.get(&index) {
+            Some(execution) => execution,
+            None => return Ok(()),
+        };
+        match killpg(Pid::from_raw(execution.child.id() as i32), signal) {
+            Ok(()) | Err(Errno::ESRCH) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("send {} to command {}", signal, index)),
+        }
+    }
+}
+
+impl Action for Pause {
+    fn apply(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        match &command.cgroup {
+            Some(path) => cgroup::write(path, "cgroup.freeze", "1"),
+            None => self.signal(index, Signal::SIGSTOP),
+        }
+    }
+
+    fn revert(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        match &command.cgroup {
+            Some(path) => cgroup::write(path, "cgroup.freeze", "0"),
+            None => self.signal(index, Signal::SIGCONT),
+        }
+    }
+}
+
+fn data_device(command: &supervisor::CommandConfig) -> Result<(&data::Mount, &disk::Device)> {
+    command
+        .data
+        .as_ref()
+        .and_then(|mount| mount.device.as_ref().map(|device| (mount, device)))
+        .with_context(|| format!("command {} has no data device", command.name))
+}
+
+// DiskTable replaces the table of the data device, and restores linear table on revert.
+struct DiskTable {
+    table: disk::Table,
+}
+
+impl Action for DiskTable {
+    fn apply(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        data_device(command)?.1.set(self.table)
+    }
+
+    fn revert(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        data_device(command)?.1.set(disk::Table::Linear)
+    }
+}
+
+struct DiskFull;
+
+impl Action for DiskFull {
+    fn apply(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        disk::fill(&data_device(command)?.0.source)
+    }
+
+    fn revert(&mut self, _: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        disk::unfill(&data_device(command)?.0.source)
+    }
+}
+
+// Kill stops the instance the same way as on shutdown, but with the configured signal,
+// and launches it with the same config after duration. namespace and address are preserved.
+struct Kill {
+    signal: supervisor::Signal,
+    processes: Processes,
+    // instances stopped by this fault, other kill faults may target the same instance
+    stopped: BTreeSet<usize>,
+}
+
+impl Action for Kill {
+    fn apply(&mut self, index: usize, _: &supervisor::CommandConfig) -> Result<()> {
+        if supervisor::stop_one(
+            &self.processes.tasks,
+            index,
+            self.signal,
+            self.processes.stop_timeout,
+        )? {
+            self.stopped.insert(index);
+        } else {
+            tracing::warn!("command {} is not running, not killing it", index);
+        }
+        Ok(())
+    }
+
+    fn revert(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        if !self.stopped.remove(&index) {
+            return Ok(());
+        }
+        supervisor::start_one(
+            command,
+            &mut self.processes.tasks.lock().unwrap(),
+            index,
+            &self.processes.errors,
+        )
+    }
+
+    // there is no point in starting instance that will be stopped right away
+    fn abort(&mut self, index: usize, _: &supervisor::CommandConfig) -> Result<()> {
+        self.stopped.remove(&index);
+        Ok(())
+    }
+}
+
+struct Capture {
+    size: u64,
+    processes: BTreeMap<usize, capture::Process>,
+}
+
+impl Action for Capture {
+    // every application is written to new files <work_dir>/<namespace>.<unix time>.pcap<n>
+    fn apply(&mut self, index: usize, command: &supervisor::CommandConfig) -> Result<()> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let name = format!("{}.{}", command.name, now.as_secs());
+        let process = capture::Process::start(
+            Some(&network::Namespace {
+                name: command.name.clone(),
+            }),
+            &network::guest_device(&command.name),
+            &capture::path(&command.work_dir, &name),
+            self.size,
+            capture::DEFAULT_KEEP,
+            None,
+        )?;
+        self.processes.insert(index, process);
+        Ok(())
+    }
+
+    fn revert(&mut self, index: usize, _: &supervisor::CommandConfig) -> Result<()> {
+        match self.processes.remove(&index) {
+            Some(process) => process.stop(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct Task {
+    fault: Fault,
+    commands: BTreeMap<usize, supervisor::CommandConfig>,
+    action: Box<dyn Action>,
+    applied: Vec<usize>,
+    metrics: Arc<metrics::Registry>,
+}
+
+impl Task {
+    pub(crate) fn new(
+        fault: Fault,
+        commands: BTreeMap<usize, supervisor::CommandConfig>,
+        processes: Processes,
+    ) -> Result<Self> {
+        if matches!(fault.kind, Kind::Cpu(_) | Kind::Memory(_)) {
+            for command in commands.values() {
+                cgroup_path(command)?;
+            }
+        }
+        if matches!(
+            fault.kind,
+            Kind::DiskDelay(_) | Kind::DiskError | Kind::DiskFull
+        ) {
+            for command in commands.values() {
+                data_device(command)?;
+            }
+        }
+        let metrics = processes.metrics.clone();
+        let action = fault.kind.action(processes);
+        Ok(Self {
+            fault,
+            commands,
+            action,
+            applied: vec![],
+            metrics,
+        })
+    }
+
+    // triggered creates task for the fault that was triggered by the output of the instance.
+    pub(crate) fn triggered(
+        fault: Fault,
+        index: usize,
+        commands: &BTreeMap<usize, supervisor::CommandConfig>,
+        processes: Processes,
+    ) -> Result<Self> {
+        let commands = match fault.target {
+            Target::Matched => commands
+                .get(&index)
+                .map(|command| (index, command.clone()))
+                .into_iter()
+                .collect(),
+            _ => commands.clone(),
+        };
+        Self::new(fault, commands, processes)
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        let instances: Vec<usize> = self.commands.keys().copied().collect();
+        for index in self.fault.target.select(&instances) {
+            tracing::info!("applying {:?} to command {}", self.fault.kind, index);
+            self.action.apply(index, &self.commands[&index])?;
+            self.applied.push(index);
+            self.metrics.fault_applied(self.fault.kind.name());
+        }
+        Ok(())
+    }
+
+    // revert is attempted for every instance, first error is returned.
+    fn revert(&mut self, abort: bool) -> Result<()> {
+        let mut rst = Ok(());
+        for index in self.applied.drain(..) {
+            tracing::info!("reverting {:?} for command {}", self.fault.kind, index);
+            self.metrics.fault_reverted(self.fault.kind.name());
+            let command = &self.commands[&index];
+            let reverted = if abort {
+                self.action.abort(index, command)
+            } else {
+                self.action.revert(index, command)
+            };
+            if let Err(err) = reverted {
+                if rst.is_ok() {
+                    rst = Err(err);
+                }
+            }
+        }
+        rst
+    }
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handler: JoinHandle<()>,
+}
+
+impl Background {
+    pub(crate) fn spawn(mut task: Task) -> Result<Self> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let handle = spawn(move || {
+            loop {
+                if let Some(interval) = task.fault.interval {
+                    select! {
+                        recv(receiver) -> _ => break,
+                        default(interval.into()) => {},
+                    }
+                }
+                if let Err(err) = task.apply() {
+                    tracing::error!("failed to apply fault: {:?}", err);
+                }
+                select! {
+                    recv(receiver) -> _ => break,
+                    default(task.fault.duration.into()) => {},
+                }
+                if let Err(err) = task.revert(false) {
+                    tracing::error!("failed to revert fault: {:?}", err);
+                }
+                if task.fault.interval.is_none() {
+                    break;
+                }
+            }
+            tracing::debug!("stopping fault task");
+            // fault must not outlive the playground, e.g. instances must not be left throttled
+            if let Err(err) = task.revert(true) {
+                tracing::error!("failed to revert fault: {:?}", err);
+            }
+        });
+        Ok(Self {
+            sender,
+            handler: handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        _ = self.sender.send(());
+        self.handler.join().unwrap();
+    }
+
+    // is_finished is true when triggered fault was applied and reverted.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handler.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let fault = Fault::parse("cpu 0.1 target 0,2,5-7 interval 30s duration 10s").unwrap();
+        assert!(matches!(fault.kind, Kind::Cpu(cpu) if cpu == 0.1));
+        assert_eq!(fault.target, Target::Indices(vec![0, 2, 5, 6, 7]));
+        assert_eq!(
+            fault.interval.map(|interval| *interval),
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert_eq!(*fault.duration, std::time::Duration::from_secs(10));
+
+        let fault = Fault::parse("memory 64M interval 1m duration 20s").unwrap();
+        assert!(matches!(fault.kind, Kind::Memory(high) if high == 64 << 20));
+        assert_eq!(fault.target, Target::All);
+
+        let fault = Fault::parse("cpu 0.5 target bucket 0.5..1.0 interval 1s duration 1s").unwrap();
+        assert_eq!(fault.target, Target::Bucket(0.5, 1.0));
+
+        assert!(Fault::parse("cpu interval 1s duration 1s").is_err());
+        assert!(Fault::parse("cpu 0.5 target random interval 1s duration 1s").is_err());
+        assert!(Fault::parse("cpu 0.5 interval 1s").is_err());
+        assert!(Fault::parse("disk 0.5 interval 1s duration 1s").is_err());
+
+        let fault = Fault::parse("pause target random 1 interval 10s duration 2s").unwrap();
+        assert!(matches!(fault.kind, Kind::Pause));
+        assert_eq!(fault.target, Target::Random(1));
+        assert!(Fault::parse("pause 1 interval 10s duration 2s").is_err());
+
+        let fault = Fault::parse("kill target 3 interval 1m duration 5s").unwrap();
+        assert!(matches!(
+            fault.kind,
+            Kind::Kill(supervisor::Signal::SIGKILL)
+        ));
+        let fault = Fault::parse("kill TERM target 3 interval 1m duration 5s").unwrap();
+        assert!(matches!(
+            fault.kind,
+            Kind::Kill(supervisor::Signal::SIGTERM)
+        ));
+        assert!(Fault::parse("kill NOPE interval 1m duration 5s").is_err());
+
+        let fault = Fault::parse("disk-delay 100ms target 1 interval 1m duration 5s").unwrap();
+        assert!(
+            matches!(fault.kind, Kind::DiskDelay(delay) if *delay == std::time::Duration::from_millis(100))
+        );
+        assert!(Fault::parse("disk-error target 1 interval 1m duration 5s").is_ok());
+        assert!(Fault::parse("disk-full 1G interval 1m duration 5s").is_err());
+
+        let fault = Fault::parse("capture target 0,1 interval 1m duration 10s").unwrap();
+        assert!(matches!(fault.kind, Kind::Capture(size) if size == capture::DEFAULT_SIZE));
+        let fault = Fault::parse("capture 10M interval 1m duration 10s").unwrap();
+        assert!(matches!(fault.kind, Kind::Capture(size) if size == 10 << 20));
+
+        let fault = Fault::parse_triggered("kill duration 5s").unwrap();
+        assert_eq!(fault.target, Target::Matched);
+        assert!(fault.interval.is_none());
+        let fault = Fault::parse_triggered("pause target random 2 duration 5s").unwrap();
+        assert_eq!(fault.target, Target::Random(2));
+        assert!(Fault::parse_triggered("pause interval 1m duration 5s").is_err());
+        assert!(Fault::parse("pause duration 5s").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let instances = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(Target::All.select(&instances), instances);
+        assert_eq!(
+            Target::Indices(vec![1, 3, 10]).select(&instances),
+            vec![1, 3]
+        );
+        assert_eq!(Target::Bucket(0.0, 0.25).select(&instances), vec![0, 1]);
+        assert_eq!(
+            Target::Bucket(0.5, 1.0).select(&instances),
+            vec![4, 5, 6, 7]
+        );
+        let random = Target::Random(3).select(&instances);
+        assert_eq!(random.len(), 3);
+        assert!(random.iter().all(|index| instances.contains(index)));
+        assert_eq!(Target::Random(10).select(&instances).len(), 8);
+    }
+}
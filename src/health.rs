@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::Ipv4Addr,
+    process::ExitStatus,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    thread::{self, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use crossbeam::{channel::Sender, select};
+use serde::{Deserialize, Serialize};
+
+use crate::{state, supervisor};
+
+const STATUS_FILE: &str = "status.json";
+// probes of different instances are executed in parallel by this number of threads
+const PROBE_WORKERS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub interval: Duration,
+    // instances are not checked for this long after they were started or restarted
+    pub start_period: Duration,
+    // number of consecutive failed checks before instance is considered unhealthy
+    pub failures: usize,
+    // restart instances that became unhealthy
+    pub restart: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            start_period: Duration::ZERO,
+            failures: 3,
+            restart: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    // instance is running and has no health probe
+    Running,
+    // health probe didn't succeed yet
+    Starting,
+    Healthy,
+    Unhealthy,
+    // instance without health probe exited
+    Exited,
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            State::Running => "running",
+            State::Starting => "starting",
+            State::Healthy => "healthy",
+            State::Unhealthy => "unhealthy",
+            State::Exited => "exited",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub index: usize,
+    pub name: String,
+    pub hostname: String,
+    pub ip: Ipv4Addr,
+    pub pid: u32,
+    pub state: State,
+    // consecutive failed checks
+    pub failures: usize,
+    pub restarts: usize,
+}
+
+impl Status {
+    // observe records result of the check and returns new state if it changed.
+    fn observe(&mut self, healthy: bool, threshold: usize) -> Option<State> {
+        let state = if healthy {
+            self.failures = 0;
+            State::Healthy
+        } else {
+            self.failures += 1;
+            if self.failures < threshold {
+                return None;
+            }
+            State::Unhealthy
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+// read_status returns last status of every instance reported by the running playground.
+pub fn read_status(prefix: &str) -> Result<Vec<Status>> {
+    let status = state::read(prefix, STATUS_FILE)?;
+    serde_json::from_slice(&status).context("decode status")
+}
+
+struct Snapshot {
+    pid: u32,
+    exited: Option<ExitStatus>,
+    log_matched: Option<Arc<AtomicBool>>,
+}
+
+pub(crate) struct Task {
+    prefix: String,
+    config: Config,
+    commands: BTreeMap<usize, supervisor::CommandConfig>,
+    tasks: Arc<Mutex<BTreeMap<usize, supervisor::Execution>>>,
+    errors: Sender<Result<()>>,
+    stop_signal: supervisor::Signal,
+    stop_timeout: Duration,
+    status: BTreeMap<usize, Status>,
+    // when instances were started or restarted
+    started: BTreeMap<usize, Instant>,
+}
+
+impl Task {
+    pub(crate) fn new(
+        prefix: String,
+        config: Config,
+        commands: BTreeMap<usize, supervisor::CommandConfig>,
+        tasks: Arc<Mutex<BTreeMap<usize, supervisor::Execution>>>,
+        errors: Sender<Result<()>>,
+        stop_signal: supervisor::Signal,
+        stop_timeout: Duration,
+    ) -> Self {
+        let status = commands
+            .iter()
+            .map(|(index, command)| {
+                (
+                    *index,
+                    Status {
+                        index: *index,
+                        name: command.name.clone(),
+                        hostname: command.hostname.clone(),
+                        ip: command.ip,
+                        pid: 0,
+                        state: match command.health {
+                            Some(_) => State::Starting,
+                            None => State::Running,
+                        },
+                        failures: 0,
+                        restarts: 0,
+                    },
+                )
+            })
+            .collect();
+        let now = Instant::now();
+        let started = commands.keys().map(|index| (*index, now)).collect();
+        Self {
+            prefix,
+            config,
+            commands,
+            tasks,
+            errors,
+            stop_signal,
+            stop_timeout,
+            status,
+            started,
+        }
+    }
+
+    // snapshot collects state of running commands. probes are executed without holding the lock,
+    // so that faults and shutdown are not blocked by slow probes.
+    fn snapshot(&self) -> BTreeMap<usize, Snapshot> {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks
+            .iter_mut()
+            .filter_map(|(index, execution)| match execution.child.try_wait() {
+                Ok(exited) => Some((
+                    *index,
+                    Snapshot {
+                        pid: execution.child.id(),
+                        exited,
+                        log_matched: execution.log_matched.clone(),
+                    },
+                )),
+                Err(err) => {
+                    tracing::error!("failed to wait for command {}: {:?}", index, err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // probe runs health probes of running instances that are past the start period. probes are
+    // executed in parallel, so that slow probes don't stretch a round of checks past the interval.
+    fn probe(&self, snapshot: &BTreeMap<usize, Snapshot>) -> BTreeMap<usize, bool> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        for (index, command) in self.commands.iter() {
+            let probe = match &command.health {
+                Some(probe) => probe,
+                None => continue,
+            };
+            let snapshot = match snapshot.get(index) {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            if self.started[index].elapsed() < self.config.start_period {
+                continue;
+            }
+            _ = sender.send((*index, command, probe, snapshot));
+        }
+        drop(sender);
+        let workers = receiver.len().min(PROBE_WORKERS);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    scope.spawn(move || {
+                        receiver
+                            .iter()
+                            .map(|(index, command, probe, snapshot)| {
+                                let healthy = snapshot.exited.is_none()
+                                    && supervisor::check(
+                                        probe,
+                                        command,
+                                        snapshot.log_matched.as_deref(),
+                                    );
+                                (index, healthy)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    fn check(&mut self) {
+        let snapshot = self.snapshot();
+        let results = self.probe(&snapshot);
+        for (index, command) in self.commands.iter() {
+            let status = self.status.get_mut(index).expect("status must exist");
+            let Snapshot { pid, exited, .. } = match snapshot.get(index) {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            status.pid = *pid;
+            let probe = match &command.health {
+                Some(probe) => probe,
+                None => {
+                    if let (Some(exit), State::Running) = (*exited, status.state) {
+                        tracing::warn!("command {} exited: {}", index, exit);
+                        status.state = State::Exited;
+                    }
+                    continue;
+                }
+            };
+            let healthy = match results.get(index) {
+                Some(healthy) => *healthy,
+                // instance is in the start period
+                None => continue,
+            };
+            match status.observe(healthy, self.config.failures) {
+                Some(State::Healthy) => {
+                    tracing::info!("command {} is healthy", index);
+                }
+                Some(State::Unhealthy) => {
+                    tracing::warn!(
+                        "command {} is unhealthy after {} failed checks of {}",
+                        index,
+                        status.failures,
+                        probe
+                    );
+                }
+                _ => {}
+            }
+            if status.state == State::Unhealthy && self.config.restart {
+                tracing::info!("restarting command {}", index);
+                if let Err(err) = supervisor::restart(
+                    command,
+                    &self.tasks,
+                    *index,
+                    &self.errors,
+                    self.stop_signal,
+                    self.stop_timeout,
+                ) {
+                    tracing::error!("failed to restart command {}: {:?}", index, err);
+                    continue;
+                }
+                status.state = State::Starting;
+                status.failures = 0;
+                status.restarts += 1;
+                self.started.insert(*index, Instant::now());
+            }
+        }
+    }
+
+    fn report(&self) -> Result<()> {
+        let status: Vec<&Status> = self.status.values().collect();
+        state::write(&self.prefix, STATUS_FILE, &serde_json::to_vec(&status)?)
+    }
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handler: JoinHandle<()>,
+}
+
+impl Background {
+    pub(crate) fn spawn(mut task: Task) -> Result<Self> {
+        task.report()?;
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let handle = spawn(move || loop {
+            select! {
+                recv(receiver) -> _ => {
+                    tracing::debug!("stopping health task");
+                    break;
+                },
+                default(task.config.interval) => {},
+            }
+            task.check();
+            if let Err(err) = task.report() {
+                tracing::error!("failed to report status: {:?}", err);
+            }
+        });
+        Ok(Self {
+            sender,
+            handler: handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        _ = self.sender.send(());
+        self.handler.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let mut status = Status {
+            index: 0,
+            name: "p-0".to_string(),
+            hostname: "p-0".to_string(),
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            pid: 1,
+            state: State::Starting,
+            failures: 0,
+            restarts: 0,
+        };
+        assert_eq!(status.observe(false, 2), None);
+        assert_eq!(status.observe(true, 2), Some(State::Healthy));
+        assert_eq!(status.observe(true, 2), None);
+        assert_eq!(status.observe(false, 2), None);
+        assert_eq!(status.observe(false, 2), Some(State::Unhealthy));
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.observe(false, 2), None);
+        assert_eq!(status.observe(true, 2), Some(State::Healthy));
+        assert_eq!(status.failures, 0);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt::Write,
+    fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::network;
+
+// files in this directory are bind mounted over /etc when command is launched in the namespace.
+// the same convention is used by `ip netns exec`.
+const NETNS_ETC: &str = "/etc/netns";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) ip: Ipv4Addr,
+    // first name is the hostname of the instance
+    pub(crate) names: Vec<String>,
+    pub(crate) group: Option<String>,
+    pub(crate) port: Option<u16>,
+}
+
+fn etc_dir(namespace: &network::Namespace) -> PathBuf {
+    PathBuf::from(NETNS_ETC).join(&namespace.name)
+}
+
+// render hosts file that resolves names of every instance in the playground.
+pub(crate) fn render(entries: &[Entry]) -> String {
+    let mut hosts =
+        String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+    for entry in entries {
+        _ = writeln!(hosts, "{}\t{}", entry.ip, entry.names.join(" "));
+    }
+    hosts
+}
+
+// shared_path is the hosts file of the playground, it is linked into the directory of every namespace.
+fn shared_path(prefix: &str) -> PathBuf {
+    PathBuf::from(NETNS_ETC).join(format!("{}.hosts", prefix))
+}
+
+// write stores hosts file once for all namespaces of the playground.
+pub(crate) fn write(prefix: &str, hosts: &str) -> Result<PathBuf> {
+    fs::create_dir_all(NETNS_ETC).with_context(|| format!("create {}", NETNS_ETC))?;
+    let path = shared_path(prefix);
+    fs::write(&path, hosts).with_context(|| format!("write {:?}", path))?;
+    Ok(path)
+}
+
+// apply links the shared hosts file into the namespace, it is copied if link can't be created.
+pub(crate) fn apply(namespace: &network::Namespace, shared: &Path) -> Result<()> {
+    let dir = etc_dir(namespace);
+    fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+    let hosts = dir.join("hosts");
+    match fs::remove_file(&hosts) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("remove {:?}", hosts));
+        }
+        _ => {}
+    }
+    if let Err(err) = fs::hard_link(shared, &hosts) {
+        tracing::debug!("failed to link {:?}, copying it: {:?}", shared, err);
+        fs::copy(shared, &hosts).with_context(|| format!("copy hosts to {:?}", dir))?;
+    }
+    Ok(())
+}
+
+// remove deletes the shared hosts file, links in namespaces are removed with their directories.
+pub(crate) fn remove(prefix: &str) -> Result<()> {
+    let path = shared_path(prefix);
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+// resolv_apply configures namespace to use nameserver and search names in the domain.
+pub(crate) fn resolv_apply(
+    namespace: &network::Namespace,
+    nameserver: Ipv4Addr,
+    domain: &str,
+) -> Result<()> {
+    let dir = etc_dir(namespace);
+    fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+    let mut resolv = format!("nameserver {}\n", nameserver);
+    if !domain.is_empty() {
+        _ = writeln!(resolv, "search {}", domain);
+    }
+    fs::write(dir.join("resolv.conf"), resolv)
+        .with_context(|| format!("write resolv.conf in {:?}", dir))?;
+    Ok(())
+}
+
+pub(crate) fn revert(namespace: &network::Namespace) -> Result<()> {
+    let dir = etc_dir(namespace);
+    match fs::remove_dir_all(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn cleanup(prefix: &str) -> Result<usize> {
+    let entries = match fs::read_dir(NETNS_ETC) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).context("read /etc/netns"),
+    };
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(prefix) {
+            // shared hosts file is next to directories of namespaces
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())
+            } else {
+                fs::remove_file(entry.path())
+            }
+            .with_context(|| format!("remove {:?}", entry.path()))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
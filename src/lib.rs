@@ -1,17 +1,37 @@
-use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
 
 use anyhow::{ensure, Result};
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use ipnet::{IpAddrRange, IpNet};
 
+pub mod capture;
+pub mod cgroup;
+pub mod clock;
 pub mod core;
+pub mod data;
+mod disk;
+mod dns;
+mod exec;
+pub mod fault;
+pub mod health;
+pub mod hosts;
+pub mod logs;
+mod metrics;
 mod netlink;
 mod network;
 pub mod partition;
+pub mod probe;
 pub mod shell;
+pub mod state;
+mod stats;
 pub mod supervisor;
 mod sysctl;
+pub mod template;
+pub mod trigger;
+pub mod user;
 
 // the limit of ports enforced in the kernel is 1<<10
 // https://github.com/torvalds/linux/blob/80e62bc8487b049696e67ad133c503bf7f6806f7/net/bridge/br_private.h#L28
@@ -19,6 +39,8 @@ mod sysctl;
 //
 // TODO debug why does it fail with 1023 instances
 pub const MAX_VETH_PER_BRIDGE: usize = 1000;
+// maximal length of a dns label
+const MAX_HOSTNAME_LEN: usize = 63;
 
 pub struct Env {
     host_id: usize,
@@ -27,20 +49,62 @@ pub struct Env {
     net: IpNet,
     instances_per_bridge: usize,
     revert: bool,
-    // redirect stdout and stderr to files in the working directories
+    // redirect stdout and stderr to combined log files in the working directories
     redirect: bool,
+    // run commands with /bin/sh -c
+    shell: bool,
     vxlan_id: u32,
     vxlan_port: u16,
     vxlan_multicast_group: std::net::Ipv4Addr,
     vxlan_device: String,
+    // signal sent to commands on stop and how long to wait before killing them
+    stop_signal: supervisor::Signal,
+    stop_timeout: std::time::Duration,
+    // how long to wait for readiness probes of every group of commands
+    ready_timeout: std::time::Duration,
+    // set on interrupt, launch stops waiting for readiness probes
+    interrupted: Arc<AtomicBool>,
 
     address_pool: IpAddrRange,
     commands: BTreeMap<usize, supervisor::CommandConfig>,
-    tasks: BTreeMap<usize, supervisor::Execution>,
+    // instances on other hosts of the groups that local commands are started after
+    remote: BTreeMap<usize, supervisor::CommandConfig>,
+    // shared with health checks, that may restart commands
+    tasks: Arc<Mutex<BTreeMap<usize, supervisor::Execution>>>,
     network: Vec<core::Data>,
+    // names of all instances on all hosts
+    hosts: Vec<hosts::Entry>,
     errors_sender: Sender<anyhow::Result<()>>,
     errors_receiver: Receiver<anyhow::Result<()>>,
     partition: Option<partition::Background>,
+    faults: Vec<fault::Background>,
+    // domain served by dns server on bridge addresses, if enabled
+    dns_domain: Option<String>,
+    dns: Option<dns::Background>,
+    // private data directory of every instance
+    data: Option<data::Config>,
+    health_config: health::Config,
+    health: Option<health::Background>,
+    log_rotation: logs::Rotation,
+    // compress logs into a single archive in every working directory on stop
+    log_archive: bool,
+    // aggregated JSON-lines log of all instances on this host
+    json_log: Option<std::path::PathBuf>,
+    triggers: Vec<trigger::Trigger>,
+    // lines matched by output readers, handled by the trigger task after deploy
+    matched: Option<Receiver<trigger::Matched>>,
+    trigger: Option<trigger::Background>,
+    // how often network counters of every instance are sampled, if enabled
+    stats_interval: Option<std::time::Duration>,
+    stats: Option<stats::Background>,
+    // updated by partition, faults and stats, even if metrics are not served
+    metrics: Arc<metrics::Registry>,
+    // port of /metrics served on localhost and bridge addresses, if enabled
+    metrics_port: Option<u16>,
+    metrics_server: Option<metrics::Background>,
+    captures: Vec<capture::Capture>,
+    // tcpdump processes started on deploy, stopped after instances
+    capture: Vec<capture::Process>,
 }
 
 impl Env {
@@ -52,10 +116,14 @@ impl Env {
         per_bridge: usize,
         revert: bool,
         redirect: bool,
+        shell: bool,
         vxlan_id: u32,
         vxlan_port: u16,
         vxlan_multicast_group: std::net::Ipv4Addr,
         vxlan_device: String,
+        stop_signal: supervisor::Signal,
+        stop_timeout: std::time::Duration,
+        ready_timeout: std::time::Duration,
     ) -> Self {
         let (sender, receiver) = unbounded();
         let hosts = net.hosts();
@@ -67,18 +135,44 @@ impl Env {
             instances_per_bridge: per_bridge,
             revert,
             redirect,
+            shell,
             vxlan_id,
             vxlan_port,
             vxlan_multicast_group,
             vxlan_device,
+            stop_signal,
+            stop_timeout,
+            ready_timeout,
+            interrupted: Arc::new(AtomicBool::new(false)),
 
             address_pool: hosts,
             commands: BTreeMap::new(),
-            tasks: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            tasks: Arc::new(Mutex::new(BTreeMap::new())),
             network: vec![],
+            hosts: vec![],
             errors_sender: sender,
             errors_receiver: receiver,
             partition: None,
+            faults: vec![],
+            dns_domain: None,
+            dns: None,
+            data: None,
+            health_config: health::Config::default(),
+            health: None,
+            log_rotation: logs::Rotation::default(),
+            log_archive: false,
+            json_log: None,
+            triggers: vec![],
+            matched: None,
+            trigger: None,
+            stats_interval: None,
+            stats: None,
+            metrics: Arc::new(metrics::Registry::default()),
+            metrics_port: None,
+            metrics_server: None,
+            captures: vec![],
+            capture: vec![],
         }
     }
 
@@ -93,19 +187,93 @@ impl Env {
             .flat_map(|data| data.veth.values())
             .map(|veth| veth.clone())
             .collect();
-        let task = partition::Task::new(partition, veths);
+        let task = partition::Task::new(partition, veths, self.metrics.clone());
         self.partition = Some(partition::Background::spawn(task)?);
         Ok(())
     }
 
+    // enable_fault schedules the fault for instances on this host. must be called after deploy.
+    pub fn enable_fault(&mut self, fault: fault::Fault) -> Result<()> {
+        let task = fault::Task::new(
+            fault,
+            self.commands.clone(),
+            fault::Processes {
+                tasks: self.tasks.clone(),
+                errors: self.errors_sender.clone(),
+                stop_timeout: self.stop_timeout,
+                metrics: self.metrics.clone(),
+            },
+        )?;
+        self.faults.push(fault::Background::spawn(task)?);
+        Ok(())
+    }
+
+    // enable_dns serves names of instances and groups from bridge addresses,
+    // and configures every namespace to use it. must be called before deploy.
+    pub fn enable_dns(&mut self, domain: String) {
+        self.dns_domain = Some(domain);
+    }
+
+    // enable_interrupt makes deploy stop waiting for readiness of commands once the flag is set.
+    pub fn enable_interrupt(&mut self, interrupted: Arc<AtomicBool>) {
+        self.interrupted = interrupted;
+    }
+
+    // enable_health changes how often instances are checked and what happens with unhealthy instances.
+    // status of instances is reported even if it is not called. must be called before deploy.
+    pub fn enable_health(&mut self, config: health::Config) {
+        self.health_config = config;
+    }
+
+    // enable_data gives every instance a private directory <work_dir>/<namespace>,
+    // that is mounted to the target path. must be called before generate.
+    pub fn enable_data(&mut self, config: data::Config) {
+        self.data = Some(config);
+    }
+
+    // enable_log_rotation limits redirected logs of every instance, and optionally archives them
+    // into <work_dir>/<prefix>.logs.tar.gz on stop. must be called before generate.
+    pub fn enable_log_rotation(&mut self, rotation: logs::Rotation, archive: bool) {
+        self.log_rotation = rotation;
+        self.log_archive = archive;
+    }
+
+    // enable_json_log writes every line of output of all instances on this host to the file
+    // as a JSON object. must be called before generate.
+    pub fn enable_json_log(&mut self, path: std::path::PathBuf) {
+        self.json_log = Some(path);
+    }
+
+    // enable_trigger executes the action of the trigger when output of any instance matches it.
+    // must be called before generate.
+    pub fn enable_trigger(&mut self, trigger: trigger::Trigger) {
+        self.triggers.push(trigger);
+    }
+
+    // enable_net_stats samples link and qdisc counters of every instance every interval,
+    // and writes them to <work_dir>/<namespace>.net.csv. must be called before deploy.
+    pub fn enable_net_stats(&mut self, interval: std::time::Duration) {
+        self.stats_interval = Some(interval);
+    }
+
+    // enable_metrics serves prometheus metrics of this host on http://<addr>:<port>/metrics,
+    // where addr is localhost and address of every bridge. must be called before deploy.
+    pub fn enable_metrics(&mut self, port: u16) {
+        self.metrics_port = Some(port);
+    }
+
+    // enable_capture records packets of the targeted instances or bridges from deploy until stop,
+    // into pcap files next to logs. must be called before deploy.
+    pub fn enable_capture(&mut self, capture: capture::Capture) {
+        self.captures.push(capture);
+    }
+
     pub fn generate(
         &mut self,
-        commands: impl Iterator<Item = String> + Clone,
+        instances: impl Iterator<Item = supervisor::Instance> + Clone,
         qdisc: impl Iterator<Item = (Option<String>, Option<String>)>,
-        env: impl Iterator<Item = BTreeMap<String, String>>,
-        workdir: impl Iterator<Item = PathBuf>,
     ) -> Result<()> {
-        let total_commands = commands.clone().count();
+        let total_commands = instances.clone().count();
 
         let hosts = (0..self.total_hosts).scan(0, |last, host| {
             let current = *last;
@@ -130,13 +298,14 @@ impl Env {
             &mut self.address_pool,
             qdisc,
         )?;
-        let commands = supervisor::generate(
+        let contexts = template::contexts(&network)?;
+        let mut commands = supervisor::generate(
             &self.prefix,
             self.redirect,
+            self.shell,
             hosts.clone(),
-            commands,
-            env,
-            workdir,
+            instances,
+            &contexts,
         )?;
 
         ensure!(
@@ -150,8 +319,138 @@ impl Env {
             commands.len(),
         );
 
+        let cgroups = cgroup::available();
+        for command in commands.iter_mut().flat_map(|host| host.values_mut()) {
+            if cgroups {
+                command.cgroup = Some(cgroup::path(&self.prefix, &command.name));
+            } else {
+                ensure!(
+                    command.limits.is_empty(),
+                    "cgroup v2 is not available, limits for command {} can't be applied",
+                    command.name
+                );
+            }
+        }
+
+        // aggregated log is opened once and shared by all instances
+        let json_log = match &self.json_log {
+            Some(path) => Some(Arc::new(logs::Json::open(path, self.host_id)?)),
+            None => None,
+        };
+        for command in commands.iter_mut().flat_map(|host| host.values_mut()) {
+            command.log_rotation = self.log_rotation.clone();
+            command.json_log = json_log.clone();
+        }
+
+        if !self.triggers.is_empty() {
+            let (matcher, receiver) = trigger::Matcher::new(&self.triggers)?;
+            for command in commands.iter_mut().flat_map(|host| host.values_mut()) {
+                command.triggers = Some(matcher.clone());
+            }
+            self.matched = Some(receiver);
+        }
+
+        if let Some(config) = &self.data {
+            // mount is created after command changed its directory to work_dir
+            let target = std::path::absolute(&config.target)?;
+            for command in commands.iter_mut().flat_map(|host| host.values_mut()) {
+                let source = command.work_dir.join(&command.name);
+                command.data = Some(data::Mount {
+                    device: config
+                        .size
+                        .map(|size| disk::Device::new(&command.name, &source, size)),
+                    source,
+                    target: target.clone(),
+                });
+            }
+        }
+
+        let groups: BTreeSet<&str> = commands
+            .iter()
+            .flat_map(|host| host.values())
+            .filter_map(|command| command.group.as_deref())
+            .collect();
+        for command in commands.iter().flat_map(|host| host.values()) {
+            ensure!(
+                !matches!(command.health, Some(probe::Probe::Log(_))),
+                "log probe can't be used for health checks of command {}",
+                command.name
+            );
+            for after in &command.after {
+                ensure!(
+                    groups.contains(after.as_str()),
+                    "command {} is started after unknown group {}",
+                    command.name,
+                    after
+                );
+            }
+        }
+
+        self.hosts = commands
+            .iter()
+            .flat_map(|host| host.iter())
+            .map(|(index, command)| {
+                let mut names = vec![command.hostname.clone()];
+                if command.hostname != command.name {
+                    names.push(command.name.clone());
+                }
+                Ok(hosts::Entry {
+                    ip: contexts
+                        .get(index)
+                        .ok_or_else(|| anyhow::anyhow!("no context for command {}", index))?
+                        .ip,
+                    names,
+                    group: command.group.clone(),
+                    port: command.port,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut names = BTreeSet::new();
+        for name in self.hosts.iter().flat_map(|entry| entry.names.iter()) {
+            // hostnames are resolved as a single dns label, with or without the domain
+            ensure!(
+                name.len() <= MAX_HOSTNAME_LEN,
+                "hostname {} is longer than {} characters",
+                name,
+                MAX_HOSTNAME_LEN
+            );
+            ensure!(
+                names.insert(name.as_str()),
+                "hostname {} is used by more than one instance, names of commands must be distinct",
+                name
+            );
+        }
+        let local = self.host_id - 1;
+        let after: BTreeSet<&str> = commands[local]
+            .values()
+            .flat_map(|command| command.after.iter().map(|after| after.as_str()))
+            .collect();
+        self.remote = commands
+            .iter()
+            .enumerate()
+            .filter(|(host, _)| *host != local)
+            .flat_map(|(_, host)| host.iter())
+            .filter(|(_, command)| {
+                command
+                    .group
+                    .as_deref()
+                    .is_some_and(|group| after.contains(group))
+            })
+            .map(|(index, command)| (*index, command.clone()))
+            .collect();
+        for command in self.remote.values() {
+            if let Some(ready) = &command.ready {
+                ensure!(
+                    ready.remote(),
+                    "instance {} of group {} runs on another host and can't be checked with probe {}, only tcp and http probes can",
+                    command.name,
+                    command.group.as_deref().unwrap_or_default(),
+                    ready
+                );
+            }
+        }
         self.network = network;
-        self.commands = commands[self.host_id - 1].clone();
+        self.commands = commands[local].clone();
         Ok(())
     }
 
@@ -166,22 +465,200 @@ impl Env {
         tracing::info!("configured network in {:?}", since.elapsed());
 
         let since = std::time::Instant::now();
-        supervisor::launch(&self.commands, &mut self.tasks, &self.errors_sender)?;
+        let hosts = hosts::write(&self.prefix, &hosts::render(&self.hosts))?;
+        for veth in self.network[self.host_id - 1].veth.values() {
+            hosts::apply(&veth.namespace, &hosts)?;
+        }
+        tracing::info!("configured hosts in {:?}", since.elapsed());
+
+        if let Some(domain) = &self.dns_domain {
+            let data = &self.network[self.host_id - 1];
+            self.dns = Some(dns::Background::spawn(
+                dns::Zone::new(domain, &self.hosts),
+                data.bridges.values().map(|bridge| bridge.addr.ip4()),
+            )?);
+            for veth in data.veth.values() {
+                let bridge = data
+                    .bridges
+                    .get(&veth.bridge)
+                    .ok_or_else(|| anyhow::anyhow!("no bridge"))?;
+                hosts::resolv_apply(&veth.namespace, bridge.addr.ip4(), domain)?;
+            }
+            tracing::info!("dns server started for domain {}", domain);
+        }
+
+        for command in self.commands.values() {
+            if let Some(mount) = &command.data {
+                data::prepare(mount, command.user.as_ref())?;
+            }
+        }
+
+        if cgroup::available() {
+            let since = std::time::Instant::now();
+            cgroup::setup(&self.prefix)?;
+            for command in self.commands.values() {
+                if let Some(path) = &command.cgroup {
+                    cgroup::create(path, &command.limits)?;
+                }
+            }
+            tracing::info!("configured cgroups in {:?}", since.elapsed());
+        }
+
+        if self.redirect {
+            let locations: Vec<logs::Location> = self
+                .commands
+                .iter()
+                .map(|(index, command)| logs::Location {
+                    index: *index,
+                    name: command.name.clone(),
+                    path: logs::path(&command.work_dir, &command.name),
+                })
+                .collect();
+            logs::record(&self.prefix, &locations)?;
+        }
+
+        // capture is started before instances, so that it includes their first packets
+        for capture in &self.captures {
+            let processes = capture.start(&self.commands, &self.network[self.host_id - 1])?;
+            self.capture.extend(processes);
+        }
+
+        supervisor::raise_nofile_limit(self.commands.len())?;
+        let since = std::time::Instant::now();
+        supervisor::launch(
+            &self.commands,
+            &self.remote,
+            &mut self.tasks.lock().unwrap(),
+            &self.errors_sender,
+            self.ready_timeout,
+            &self.interrupted,
+        )?;
         tracing::info!("commands started in {:?}", since.elapsed());
+
+        // link counters are exported to metrics even if csv series are not enabled
+        if self.stats_interval.is_some() || self.metrics_port.is_some() {
+            let task = stats::Task::new(
+                self.stats_interval.unwrap_or(metrics::SAMPLE_INTERVAL),
+                &self.commands,
+                &self.network[self.host_id - 1],
+                self.stats_interval.is_some(),
+                self.metrics.clone(),
+            )?;
+            self.stats = Some(stats::Background::spawn(task)?);
+        }
+
+        if let Some(port) = self.metrics_port {
+            let data = &self.network[self.host_id - 1];
+            self.metrics_server = Some(metrics::Background::spawn(
+                &self.prefix,
+                self.metrics.clone(),
+                std::iter::once(std::net::Ipv4Addr::LOCALHOST)
+                    .chain(data.bridges.values().map(|bridge| bridge.addr.ip4())),
+                port,
+            )?);
+        }
+
+        if let Some(matched) = self.matched.take() {
+            let task = trigger::Task::new(
+                self.prefix.clone(),
+                self.triggers.clone(),
+                self.commands.clone(),
+                fault::Processes {
+                    tasks: self.tasks.clone(),
+                    errors: self.errors_sender.clone(),
+                    stop_timeout: self.stop_timeout,
+                    metrics: self.metrics.clone(),
+                },
+            );
+            self.trigger = Some(trigger::Background::spawn(task, matched)?);
+        }
+
+        self.health = Some(health::Background::spawn(health::Task::new(
+            self.prefix.clone(),
+            self.health_config.clone(),
+            self.commands.clone(),
+            self.tasks.clone(),
+            self.errors_sender.clone(),
+            self.stop_signal,
+            self.stop_timeout,
+        ))?);
         Ok(())
     }
 
     pub fn clear(&mut self) -> anyhow::Result<()> {
+        if let Some(server) = self.metrics_server.take() {
+            server.stop();
+        }
+        // faults are reverted on stop, so that paused instances are resumed before they are stopped
+        if let Some(trigger) = self.trigger.take() {
+            trigger.stop();
+        }
+        for fault in self.faults.drain(..) {
+            fault.stop();
+        }
+        if let Some(health) = self.health.take() {
+            health.stop();
+        }
+        // last sample is taken before instances are stopped
+        if let Some(stats) = self.stats.take() {
+            stats.stop();
+        }
         let since = std::time::Instant::now();
-        supervisor::stop(&mut self.tasks)?;
+        // commands are taken out, so that the lock isn't held while their output is read
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        supervisor::stop(&mut tasks, self.stop_signal, self.stop_timeout)?;
         tracing::info!("commands stopped in {:?}", since.elapsed());
+        for process in self.capture.drain(..) {
+            if let Err(err) = process.stop() {
+                tracing::warn!("failed to capture packets: {:?}", err);
+            }
+        }
+        if let Some(config) = &self.data {
+            for command in self.commands.values() {
+                if let Some(mount) = &command.data {
+                    if let Err(err) = data::cleanup(mount, config.cleanup) {
+                        tracing::warn!("failed to {} data: {:?}", config.cleanup, err);
+                    }
+                }
+            }
+        }
+        if self.redirect && self.log_archive {
+            let mut dirs: BTreeMap<&std::path::Path, Vec<&str>> = BTreeMap::new();
+            for command in self.commands.values() {
+                dirs.entry(&command.work_dir)
+                    .or_default()
+                    .push(&command.name);
+            }
+            for (dir, names) in dirs {
+                if let Err(err) = logs::archive(dir, &self.prefix, &names) {
+                    tracing::warn!("failed to archive logs in {:?}: {:?}", dir, err);
+                }
+            }
+        }
+        if let Err(err) = cgroup::revert(&self.prefix) {
+            tracing::warn!("failed to remove cgroups: {:?}", err);
+        }
+        if let Err(err) = state::revert(&self.prefix) {
+            tracing::warn!("failed to remove state: {:?}", err);
+        }
 
         if let Some(partition) = self.partition.take() {
             partition.stop();
         }
+        if let Some(dns) = self.dns.take() {
+            dns.stop();
+        }
         if self.revert {
             let since = std::time::Instant::now();
             if let Some(data) = self.network.get(self.host_id - 1) {
+                for veth in data.veth.values() {
+                    if let Err(err) = hosts::revert(&veth.namespace) {
+                        tracing::warn!("failed to revert hosts: {:?}", err);
+                    }
+                }
+                if let Err(err) = hosts::remove(&self.prefix) {
+                    tracing::warn!("failed to remove hosts: {:?}", err);
+                }
                 core::cleanup(data)?;
             }
             tracing::info!("network cleaned up in {:?}", since.elapsed());
@@ -0,0 +1,541 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{shell, state, user};
+
+// locations of logs of the running playground, read by `play logs`
+const LOGS_FILE: &str = "logs.json";
+
+// Rotation limits the size of the log file of every instance. when any limit is reached
+// the file is renamed to <name>.log.1, older files are shifted and the oldest is removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rotation {
+    // rotate when file exceeds this number of bytes
+    pub max_size: Option<u64>,
+    // rotate when file was opened longer than this ago
+    pub max_age: Option<Duration>,
+    // number of rotated files that are kept
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation {
+            max_size: None,
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+pub(crate) fn path(work_dir: &Path, name: &str) -> PathBuf {
+    work_dir.join(format!("{}.log", name))
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+// Sink is a combined log of stdout and stderr of the instance. every line is prefixed
+// with the timestamp and the name of the stream.
+pub(crate) struct Sink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    rotation: Rotation,
+    owner: Option<user::Credentials>,
+}
+
+impl Sink {
+    // open appends to the existing log, so that it is preserved when instance is restarted.
+    pub(crate) fn open(
+        path: PathBuf,
+        rotation: Rotation,
+        owner: Option<user::Credentials>,
+    ) -> Result<Self> {
+        let file = open(&path, owner.as_ref())?;
+        let size = file.metadata()?.len();
+        Ok(Sink {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            rotation,
+            owner,
+        })
+    }
+
+    pub(crate) fn write(&mut self, stream: &str, line: &str) -> Result<()> {
+        if self.expired() {
+            self.rotate()?;
+        }
+        let line = format_line(SystemTime::now(), stream, line);
+        // whole line is written at once, so that readers never observe partial lines
+        self.file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("write to {:?}", self.path))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn expired(&self) -> bool {
+        self.rotation.max_size.is_some_and(|max| self.size >= max)
+            || self
+                .rotation
+                .max_age
+                .is_some_and(|max| self.opened.elapsed() >= max)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.rotation.keep == 0 {
+            remove(&self.path)?;
+        } else {
+            remove(&rotated(&self.path, self.rotation.keep))?;
+            for n in (1..self.rotation.keep).rev() {
+                rename(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+            }
+            rename(&self.path, &rotated(&self.path, 1))?;
+        }
+        self.file = open(&self.path, self.owner.as_ref())?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    host: usize,
+    index: usize,
+    namespace: &'a str,
+    stream: &'a str,
+    line: &'a str,
+}
+
+// Json is a JSON-lines log shared by all instances on the host, one entry per line of output.
+// file is opened once in append mode, and every entry is written with a single write,
+// so that entries of different instances are not interleaved.
+#[derive(Debug)]
+pub(crate) struct Json {
+    file: File,
+    host_id: usize,
+}
+
+impl Json {
+    pub(crate) fn open(path: &Path, host_id: usize) -> Result<Self> {
+        Ok(Json {
+            file: open(path, None)?,
+            host_id,
+        })
+    }
+
+    pub(crate) fn write(
+        &self,
+        index: usize,
+        namespace: &str,
+        stream: &str,
+        line: &str,
+    ) -> Result<()> {
+        let mut entry = serde_json::to_vec(&Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            host: self.host_id,
+            index,
+            namespace,
+            stream,
+            line,
+        })?;
+        entry.push(b'\n');
+        (&self.file)
+            .write_all(&entry)
+            .context("write to aggregated log")
+    }
+}
+
+fn format_line(time: SystemTime, stream: &str, line: &str) -> String {
+    format!(
+        "{} {} {}\n",
+        humantime::format_rfc3339_millis(time),
+        stream,
+        line
+    )
+}
+
+fn open(path: &Path, owner: Option<&user::Credentials>) -> Result<File> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("open {:?}", path))?;
+    if let Some(owner) = owner {
+        owner.chown(path)?;
+    }
+    Ok(file)
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("rename {:?} to {:?}", from, to))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+// files lists the log of the instance followed by rotated logs from the newest to the oldest.
+pub(crate) fn files(work_dir: &Path, name: &str) -> Vec<PathBuf> {
+    let path = path(work_dir, name);
+    let mut files = vec![];
+    if path.exists() {
+        files.push(path.clone());
+    }
+    files.extend(rotated_files(&path));
+    files
+}
+
+fn rotated_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut n = 1;
+    while rotated(path, n).exists() {
+        files.push(rotated(path, n));
+        n += 1;
+    }
+    files
+}
+
+// archive compresses logs of the instances into <work_dir>/<prefix>.logs.tar.gz and removes them.
+pub(crate) fn archive(work_dir: &Path, prefix: &str, names: &[&str]) -> Result<()> {
+    let files: Vec<PathBuf> = names
+        .iter()
+        .flat_map(|name| files(work_dir, name))
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    let archive = work_dir.join(format!("{}.logs.tar.gz", prefix));
+    let entries: Vec<String> = files
+        .iter()
+        .filter_map(|file| file.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect();
+    shell::archive(work_dir, &entries, &archive)?;
+    for file in files.iter() {
+        remove(file)?;
+    }
+    tracing::info!("archived {} log files into {:?}", files.len(), archive);
+    Ok(())
+}
+
+// Location is the combined log of the instance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub index: usize,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+pub(crate) fn record(prefix: &str, locations: &[Location]) -> Result<()> {
+    state::write(prefix, LOGS_FILE, &serde_json::to_vec(locations)?)
+}
+
+// locations returns logs recorded by the running playground. if it is not running,
+// logs of the instances with the prefix are looked up in the work dir.
+pub fn locations(prefix: &str, work_dir: &Path) -> Result<Vec<Location>> {
+    if let Ok(locations) = state::read(prefix, LOGS_FILE) {
+        return serde_json::from_slice(&locations).context("decode log locations");
+    }
+    let mut locations = vec![];
+    for entry in fs::read_dir(work_dir).with_context(|| format!("read {:?}", work_dir))? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let name = match file_name.strip_suffix(".log") {
+            Some(name) => name,
+            None => continue,
+        };
+        let index = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|index| index.parse().ok());
+        if let Some(index) = index {
+            locations.push(Location {
+                index,
+                name: name.to_string(),
+                path: entry.path(),
+            });
+        }
+    }
+    locations.sort_by_key(|location| location.index);
+    Ok(locations)
+}
+
+// Line is a single line of the combined log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub index: usize,
+    pub name: String,
+    pub timestamp: String,
+    pub stream: String,
+    pub line: String,
+}
+
+impl Line {
+    fn parse(location: &Location, line: &str) -> Option<Self> {
+        let (timestamp, rest) = line.split_once(' ')?;
+        let (stream, line) = rest.split_once(' ').unwrap_or((rest, ""));
+        Some(Line {
+            index: location.index,
+            name: location.name.clone(),
+            timestamp: timestamp.to_string(),
+            stream: stream.to_string(),
+            line: line.to_string(),
+        })
+    }
+}
+
+// Tail reads logs of the instances, and follows them across rotations.
+// files are opened only while they are read, so that thousands of logs can be followed.
+pub struct Tail {
+    logs: Vec<Followed>,
+}
+
+struct Followed {
+    location: Location,
+    // rotated logs are read once before the current log
+    started: bool,
+    // inode and read offset of the followed file
+    ino: Option<u64>,
+    offset: u64,
+    // incomplete line at the end of the file
+    pending: Vec<u8>,
+}
+
+impl Tail {
+    pub fn new(locations: Vec<Location>) -> Self {
+        Tail {
+            logs: locations
+                .into_iter()
+                .map(|location| Followed {
+                    location,
+                    started: false,
+                    ino: None,
+                    offset: 0,
+                    pending: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    // read returns lines of all instances written since the previous call, ordered by timestamp.
+    // timestamps have the same format, so they are ordered as strings.
+    pub fn read(&mut self) -> Result<Vec<Line>> {
+        let mut lines = vec![];
+        for log in self.logs.iter_mut() {
+            log.read(&mut lines)?;
+        }
+        lines.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(lines)
+    }
+}
+
+impl Followed {
+    fn read(&mut self, lines: &mut Vec<Line>) -> Result<()> {
+        let path = self.location.path.clone();
+        if !self.started {
+            self.started = true;
+            for rotated in rotated_files(&path).iter().rev() {
+                self.read_all(rotated, lines)?;
+            }
+        }
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("open {:?}", path)),
+        };
+        let ino = file.metadata()?.ino();
+        if self.ino != Some(ino) {
+            if let Some(followed) = self.ino {
+                self.read_rotated(&path, followed, lines)?;
+            }
+            self.ino = Some(ino);
+            self.offset = 0;
+        }
+        self.offset = self.read_from(file, self.offset, lines)?;
+        Ok(())
+    }
+
+    // read_rotated reads the rest of the followed file after the log was rotated. it may be rotated
+    // more than once since then, so newer rotated logs are read as well.
+    fn read_rotated(&mut self, path: &Path, followed: u64, lines: &mut Vec<Line>) -> Result<()> {
+        let mut missed = vec![];
+        for rotated in rotated_files(path) {
+            let file = match File::open(&rotated) {
+                Ok(file) => file,
+                Err(_) => break,
+            };
+            if file.metadata()?.ino() == followed {
+                missed.push((file, self.offset));
+                break;
+            }
+            missed.push((file, 0));
+        }
+        for (file, offset) in missed.into_iter().rev() {
+            self.read_from(file, offset, lines)?;
+        }
+        Ok(())
+    }
+
+    // read_from reads the file from the offset and returns offset of its end.
+    fn read_from(&mut self, mut file: File, offset: u64, lines: &mut Vec<Line>) -> Result<u64> {
+        file.seek(SeekFrom::Start(offset))
+            .with_context(|| format!("seek {:?}", self.location.path))?;
+        let read = file
+            .read_to_end(&mut self.pending)
+            .with_context(|| format!("read {:?}", self.location.path))?;
+        self.split(lines);
+        Ok(offset + read as u64)
+    }
+
+    fn read_all(&mut self, path: &Path, lines: &mut Vec<Line>) -> Result<()> {
+        match fs::read(path) {
+            Ok(content) => self.pending.extend(content),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("read {:?}", path)),
+        }
+        self.split(lines);
+        Ok(())
+    }
+
+    fn split(&mut self, lines: &mut Vec<Line>) {
+        let complete = match self.pending.iter().rposition(|b| *b == b'\n') {
+            Some(position) => position + 1,
+            None => return,
+        };
+        let content: Vec<u8> = self.pending.drain(..complete).collect();
+        lines.extend(
+            String::from_utf8_lossy(&content)
+                .lines()
+                .filter_map(|line| Line::parse(&self.location, line)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_500);
+        assert_eq!(
+            format_line(time, "stderr", "error"),
+            "1970-01-01T00:00:01.500Z stderr error\n"
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("playground-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rotation = Rotation {
+            max_size: Some(1),
+            max_age: None,
+            keep: 2,
+        };
+        let mut sink = Sink::open(path(&dir, "p-0"), rotation, None).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            sink.write("stdout", line).unwrap();
+        }
+        let files = files(&dir, "p-0");
+        assert_eq!(files.len(), 3);
+        for (file, line) in files.iter().zip(["fourth", "third", "second"]) {
+            let content = fs::read_to_string(file).unwrap();
+            assert!(content.ends_with(&format!(" stdout {}\n", line)));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_write() {
+        let path = std::env::temp_dir().join(format!("playground-json-{}.log", std::process::id()));
+        let json = Json::open(&path, 2).unwrap();
+        json.write(0, "p-0", "stdout", "first").unwrap();
+        json.write(1, "p-1", "stderr", "second \"quoted\"").unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let entries: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["host"], 2);
+        assert_eq!(entries[0]["namespace"], "p-0");
+        assert_eq!(entries[0]["line"], "first");
+        assert_eq!(entries[1]["index"], 1);
+        assert_eq!(entries[1]["stream"], "stderr");
+        assert_eq!(entries[1]["line"], "second \"quoted\"");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let dir = std::env::temp_dir().join(format!("playground-tail-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rotation = Rotation {
+            max_size: Some(1),
+            max_age: None,
+            keep: 5,
+        };
+        // prefix is unique, so that logs are looked up in the work dir, not in the state of a running playground
+        let prefix = format!("tail{}", std::process::id());
+        let name = format!("{}-1", prefix);
+        let mut sink = Sink::open(path(&dir, &name), rotation, None).unwrap();
+        sink.write("stdout", "first").unwrap();
+        sink.write("stderr", "second error").unwrap();
+
+        let locations = locations(&prefix, &dir).unwrap();
+        assert_eq!(
+            locations,
+            vec![Location {
+                index: 1,
+                name: name.clone(),
+                path: path(&dir, &name),
+            }]
+        );
+        let mut tail = Tail::new(locations);
+        let lines = tail.read().unwrap();
+        let read: Vec<(&str, &str)> = lines
+            .iter()
+            .map(|line| (line.stream.as_str(), line.line.as_str()))
+            .collect();
+        assert_eq!(read, vec![("stdout", "first"), ("stderr", "second error")]);
+
+        // lines written after several rotations are read exactly once
+        sink.write("stdout", "third").unwrap();
+        sink.write("stdout", "fourth").unwrap();
+        sink.write("stdout", "fifth").unwrap();
+        let lines = tail.read().unwrap();
+        let read: Vec<&str> = lines.iter().map(|line| line.line.as_str()).collect();
+        assert_eq!(read, vec!["third", "fourth", "fifth"]);
+        assert!(tail.read().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
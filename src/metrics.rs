@@ -0,0 +1,425 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, spawn, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
+
+use crate::{health, stats};
+
+// network counters are sampled with this interval if metrics are enabled without --net-stats
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(200);
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+// connections are served on their own threads, so that a slow client doesn't block scrapes
+const MAX_CONNECTIONS: usize = 16;
+const STATES: &[&str] = &["running", "starting", "healthy", "unhealthy", "exited"];
+
+// Registry is updated by background tasks of the playground and exported by the metrics server.
+// state of instances is read from the status reported by health checks.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    partition_active: AtomicBool,
+    partitions: AtomicU64,
+    // number of instances with the fault applied, and total number of applications by kind
+    faults: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    // last sample of network counters of every instance
+    samples: Mutex<BTreeMap<usize, (String, stats::Sample)>>,
+}
+
+impl Registry {
+    pub(crate) fn partition_applied(&self) {
+        self.partition_active.store(true, Ordering::Relaxed);
+        self.partitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn partition_reverted(&self) {
+        self.partition_active.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fault_applied(&self, kind: &'static str) {
+        let mut faults = self.faults.lock().unwrap();
+        let (active, total) = faults.entry(kind).or_default();
+        *active += 1;
+        *total += 1;
+    }
+
+    pub(crate) fn fault_reverted(&self, kind: &'static str) {
+        let mut faults = self.faults.lock().unwrap();
+        let (active, _) = faults.entry(kind).or_default();
+        *active = active.saturating_sub(1);
+    }
+
+    pub(crate) fn sample(&self, index: usize, name: &str, sample: stats::Sample) {
+        self.samples
+            .lock()
+            .unwrap()
+            .insert(index, (name.to_string(), sample));
+    }
+
+    // render writes metrics in prometheus text format.
+    fn render(&self, status: &[health::Status]) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "playground_instances",
+            "gauge",
+            "number of instances on this host by state",
+        );
+        for state in STATES {
+            let count = status
+                .iter()
+                .filter(|instance| instance.state.to_string() == *state)
+                .count();
+            _ = writeln!(out, "playground_instances{{state=\"{}\"}} {}", state, count);
+        }
+        header(
+            &mut out,
+            "playground_instance_up",
+            "gauge",
+            "1 if instance is running",
+        );
+        for instance in status {
+            let up = instance.state.to_string() != "exited";
+            _ = writeln!(
+                out,
+                "playground_instance_up{{{}}} {}",
+                labels(instance.index, &instance.name),
+                up as u8
+            );
+        }
+        header(
+            &mut out,
+            "playground_instance_restarts_total",
+            "counter",
+            "number of restarts of the instance",
+        );
+        for instance in status {
+            _ = writeln!(
+                out,
+                "playground_instance_restarts_total{{{}}} {}",
+                labels(instance.index, &instance.name),
+                instance.restarts
+            );
+        }
+
+        header(
+            &mut out,
+            "playground_partition_active",
+            "gauge",
+            "1 if partition is applied",
+        );
+        _ = writeln!(
+            out,
+            "playground_partition_active {}",
+            self.partition_active.load(Ordering::Relaxed) as u8
+        );
+        header(
+            &mut out,
+            "playground_partitions_total",
+            "counter",
+            "number of times partition was applied",
+        );
+        _ = writeln!(
+            out,
+            "playground_partitions_total {}",
+            self.partitions.load(Ordering::Relaxed)
+        );
+
+        let faults = self.faults.lock().unwrap();
+        header(
+            &mut out,
+            "playground_faults_active",
+            "gauge",
+            "number of instances with the fault applied",
+        );
+        for (kind, (active, _)) in faults.iter() {
+            _ = writeln!(
+                out,
+                "playground_faults_active{{kind=\"{}\"}} {}",
+                kind, active
+            );
+        }
+        header(
+            &mut out,
+            "playground_faults_total",
+            "counter",
+            "number of times the fault was applied to an instance",
+        );
+        for (kind, (_, total)) in faults.iter() {
+            _ = writeln!(
+                out,
+                "playground_faults_total{{kind=\"{}\"}} {}",
+                kind, total
+            );
+        }
+
+        let samples = self.samples.lock().unwrap();
+        header(
+            &mut out,
+            "playground_link_bytes_total",
+            "counter",
+            "bytes received and transmitted by the instance",
+        );
+        for (index, (name, sample)) in samples.iter() {
+            let labels = labels(*index, name);
+            _ = writeln!(
+                out,
+                "playground_link_bytes_total{{{},direction=\"rx\"}} {}",
+                labels, sample.guest.rx_bytes
+            );
+            _ = writeln!(
+                out,
+                "playground_link_bytes_total{{{},direction=\"tx\"}} {}",
+                labels, sample.guest.tx_bytes
+            );
+        }
+        header(
+            &mut out,
+            "playground_link_packets_total",
+            "counter",
+            "packets received and transmitted by the instance",
+        );
+        for (index, (name, sample)) in samples.iter() {
+            let labels = labels(*index, name);
+            _ = writeln!(
+                out,
+                "playground_link_packets_total{{{},direction=\"rx\"}} {}",
+                labels, sample.guest.rx_packets
+            );
+            _ = writeln!(
+                out,
+                "playground_link_packets_total{{{},direction=\"tx\"}} {}",
+                labels, sample.guest.tx_packets
+            );
+        }
+        header(
+            &mut out,
+            "playground_link_dropped_total",
+            "counter",
+            "packets dropped on the instance and the bridge side of the veth pair",
+        );
+        for (index, (name, sample)) in samples.iter() {
+            let labels = labels(*index, name);
+            for (side, link) in [("guest", &sample.guest), ("host", &sample.host)] {
+                _ = writeln!(
+                    out,
+                    "playground_link_dropped_total{{{},side=\"{}\",direction=\"rx\"}} {}",
+                    labels, side, link.rx_dropped
+                );
+                _ = writeln!(
+                    out,
+                    "playground_link_dropped_total{{{},side=\"{}\",direction=\"tx\"}} {}",
+                    labels, side, link.tx_dropped
+                );
+            }
+        }
+        header(
+            &mut out,
+            "playground_qdisc_drops_total",
+            "counter",
+            "packets dropped by netem and tbf of the instance",
+        );
+        for (index, (name, sample)) in samples.iter() {
+            _ = writeln!(
+                out,
+                "playground_qdisc_drops_total{{{}}} {}",
+                labels(*index, name),
+                sample.qdisc.drops
+            );
+        }
+        header(
+            &mut out,
+            "playground_qdisc_backlog_bytes",
+            "gauge",
+            "bytes queued in netem and tbf of the instance",
+        );
+        for (index, (name, sample)) in samples.iter() {
+            _ = writeln!(
+                out,
+                "playground_qdisc_backlog_bytes{{{}}} {}",
+                labels(*index, name),
+                sample.qdisc.backlog
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn labels(index: usize, name: &str) -> String {
+    format!("index=\"{}\",namespace=\"{}\"", index, name)
+}
+
+fn serve(listener: TcpListener, prefix: String, registry: Arc<Registry>, stop: Receiver<()>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    loop {
+        match stop.try_recv() {
+            Err(TryRecvError::Empty) => {}
+            _ => return,
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(err) => {
+                tracing::error!("metrics server failed to accept: {:?}", err);
+                return;
+            }
+        };
+        if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::Relaxed);
+            tracing::debug!("too many metrics connections, dropped one");
+            continue;
+        }
+        let prefix = prefix.clone();
+        let registry = registry.clone();
+        let connections = connections.clone();
+        spawn(move || {
+            if let Err(err) = respond(stream, &prefix, &registry) {
+                tracing::debug!("failed to respond to metrics request: {:?}", err);
+            }
+            connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, prefix: &str, registry: &Registry) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request = String::new();
+    let mut reader = BufReader::new(&stream);
+    reader.read_line(&mut request)?;
+    // headers are read, so that the connection isn't reset before response is read
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => (
+            "200 OK",
+            // status is not available until instances are launched
+            registry.render(&health::read_status(prefix).unwrap_or_default()),
+        ),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .context("write metrics response")
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handlers: Vec<JoinHandle<()>>,
+}
+
+impl Background {
+    // spawn serves /metrics on the port of every address.
+    pub(crate) fn spawn(
+        prefix: &str,
+        registry: Arc<Registry>,
+        addrs: impl Iterator<Item = Ipv4Addr>,
+        port: u16,
+    ) -> Result<Self> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut handlers = vec![];
+        for addr in addrs {
+            let listener = TcpListener::bind((addr, port))
+                .with_context(|| format!("bind metrics server to {}:{}", addr, port))?;
+            listener.set_nonblocking(true)?;
+            let prefix = prefix.to_string();
+            let registry = registry.clone();
+            let receiver = receiver.clone();
+            handlers.push(spawn(move || serve(listener, prefix, registry, receiver)));
+            tracing::info!("serving metrics on http://{}:{}/metrics", addr, port);
+        }
+        Ok(Self { sender, handlers })
+    }
+
+    pub(crate) fn stop(self) {
+        // dropping sender disconnects the channel for every serving thread
+        drop(self.sender);
+        for handler in self.handlers {
+            _ = handler.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::default();
+        registry.partition_applied();
+        registry.fault_applied("pause");
+        registry.fault_applied("pause");
+        registry.fault_reverted("pause");
+        registry.sample(
+            1,
+            "p-1",
+            stats::Sample {
+                timestamp: std::time::SystemTime::UNIX_EPOCH,
+                guest: stats::Link {
+                    rx_bytes: 100,
+                    ..Default::default()
+                },
+                host: stats::Link::default(),
+                qdisc: stats::Qdisc {
+                    backlog: 300,
+                    ..Default::default()
+                },
+            },
+        );
+        let status = vec![health::Status {
+            index: 1,
+            name: "p-1".to_string(),
+            hostname: "p-1".to_string(),
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            pid: 1,
+            state: health::State::Healthy,
+            failures: 0,
+            restarts: 2,
+        }];
+        let rendered = registry.render(&status);
+        for line in [
+            "playground_instances{state=\"healthy\"} 1",
+            "playground_instances{state=\"exited\"} 0",
+            "playground_instance_up{index=\"1\",namespace=\"p-1\"} 1",
+            "playground_instance_restarts_total{index=\"1\",namespace=\"p-1\"} 2",
+            "playground_partition_active 1",
+            "playground_faults_active{kind=\"pause\"} 1",
+            "playground_faults_total{kind=\"pause\"} 2",
+            "playground_link_bytes_total{index=\"1\",namespace=\"p-1\",direction=\"rx\"} 100",
+            "playground_qdisc_backlog_bytes{index=\"1\",namespace=\"p-1\"} 300",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{} is missing in\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+}
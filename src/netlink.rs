@@ -1,19 +1,26 @@
 #![allow(dead_code)]
 
-use std::{net::Ipv4Addr, os::fd::AsFd};
+use std::{
+    ffi::CString,
+    io,
+    net::Ipv4Addr,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use netavark::network::{
     core_utils::open_netlink_sockets,
     netlink::{self, LinkID},
 };
-use netlink_packet_route::link::{InfoData, InfoKind, InfoVeth, LinkMessage};
+use netlink_packet_route::link::{InfoData, InfoKind, InfoVeth, LinkAttribute, LinkMessage};
 use netns_rs::NetNs;
+use nix::sched::{setns, CloneFlags};
 
-use crate::network;
+use crate::{network, stats};
 
-fn ns_path(ns: &network::Namespace) -> String {
+pub(crate) fn ns_path(ns: &network::Namespace) -> String {
     format!("/var/run/netns/{}", ns.name)
 }
 
@@ -96,3 +103,148 @@ pub(crate) fn veth_revert(veth: &network::NamespaceVeth) -> Result<()> {
     }
     Ok(())
 }
+
+// link_stats returns counters of the guest and the host devices of the veth pair.
+pub(crate) fn link_stats(veth: &network::NamespaceVeth) -> Result<(stats::Link, stats::Link)> {
+    let (mut host, mut ns) = open_netlink_sockets(&ns_path(&veth.namespace))?;
+    let guest = ns.netlink.get_link(LinkID::Name(veth.guest()))?;
+    let host = host.netlink.get_link(LinkID::Name(veth.host()))?;
+    Ok((link_counters(&guest), link_counters(&host)))
+}
+
+fn link_counters(link: &LinkMessage) -> stats::Link {
+    link.attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            LinkAttribute::Stats64(stats) => Some(stats::Link {
+                rx_bytes: stats.rx_bytes,
+                rx_packets: stats.rx_packets,
+                rx_errors: stats.rx_errors,
+                rx_dropped: stats.rx_dropped,
+                tx_bytes: stats.tx_bytes,
+                tx_packets: stats.tx_packets,
+                tx_errors: stats.tx_errors,
+                tx_dropped: stats.tx_dropped,
+            }),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+// in_namespace runs f on a thread that entered the network namespace. sockets opened by f stay in
+// the namespace, while the playground keeps running in the host namespace.
+pub(crate) fn in_namespace<T: Send>(
+    namespace: &network::Namespace,
+    f: impl FnOnce() -> Result<T> + Send,
+) -> Result<T> {
+    let file = std::fs::File::open(ns_path(namespace))
+        .with_context(|| format!("open network namespace {}", namespace.name))?;
+    std::thread::scope(|scope| {
+        scope
+            .spawn(move || {
+                setns(&file, CloneFlags::CLONE_NEWNET)
+                    .with_context(|| format!("enter network namespace {}", namespace.name))?;
+                f()
+            })
+            .join()
+            .map_err(|_| {
+                anyhow::anyhow!("thread in network namespace {} panicked", namespace.name)
+            })?
+    })
+}
+
+const RTM_GETQDISC: u16 = 38;
+// size of nlmsghdr and tcmsg
+const NLMSG_HDRLEN: usize = 16;
+const TCMSG_LEN: usize = 20;
+
+// QdiscSocket is a route netlink socket in the namespace of the instance, it is opened once, so that
+// sampling doesn't fork tc for every instance.
+pub(crate) struct QdiscSocket {
+    socket: OwnedFd,
+    // index of the guest device
+    index: i32,
+    seq: AtomicU32,
+}
+
+impl QdiscSocket {
+    pub(crate) fn open(veth: &network::NamespaceVeth) -> Result<Self> {
+        let device = veth.guest();
+        in_namespace(&veth.namespace, || {
+            let name = CString::new(device.as_str())?;
+            // SAFETY: name is a valid nul-terminated string
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if index == 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("find device {}", device));
+            }
+            // SAFETY: socket doesn't take pointers, returned descriptor is owned right away
+            let socket = unsafe {
+                let fd = libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_ROUTE,
+                );
+                if fd < 0 {
+                    return Err(io::Error::last_os_error()).context("create netlink socket");
+                }
+                OwnedFd::from_raw_fd(fd)
+            };
+            Ok(Self {
+                socket,
+                index: index as i32,
+                seq: AtomicU32::new(0),
+            })
+        })
+    }
+
+    // stats dumps queueing disciplines in the namespace and sums counters of the guest device.
+    pub(crate) fn stats(&self) -> Result<stats::Qdisc> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut request = Vec::with_capacity(NLMSG_HDRLEN + TCMSG_LEN);
+        request.extend_from_slice(&((NLMSG_HDRLEN + TCMSG_LEN) as u32).to_ne_bytes());
+        request.extend_from_slice(&RTM_GETQDISC.to_ne_bytes());
+        request.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        request.extend_from_slice(&seq.to_ne_bytes());
+        request.extend_from_slice(&0u32.to_ne_bytes());
+        // tcmsg with family, padding, ifindex, handle, parent and info
+        request.extend_from_slice(&[0u8; 4]);
+        request.extend_from_slice(&self.index.to_ne_bytes());
+        request.extend_from_slice(&[0u8; 12]);
+        // SAFETY: request is valid for reads of its length, kernel is the default destination
+        let sent = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error()).context("request qdisc dump");
+        }
+        let mut qdisc = stats::Qdisc::default();
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            // SAFETY: buf is valid for writes of its length
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err).context("receive qdisc dump");
+            }
+            if qdisc.parse(&buf[..len as usize], seq, self.index)? {
+                return Ok(qdisc);
+            }
+        }
+    }
+}
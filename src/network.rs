@@ -90,7 +90,7 @@ impl NamespaceVeth {
     }
 
     pub(crate) fn guest(&self) -> String {
-        format!("v-{}-ns", self.namespace.name)
+        guest_device(&self.namespace.name)
     }
 
     pub(crate) fn host(&self) -> String {
@@ -98,6 +98,11 @@ impl NamespaceVeth {
     }
 }
 
+// guest_device is the side of the veth pair in the namespace.
+pub(crate) fn guest_device(namespace: &str) -> String {
+    format!("v-{}-ns", namespace)
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct Qdisc {
     pub(crate) tbf: Option<String>,
@@ -1,5 +1,6 @@
 use std::{
     collections::HashSet,
+    sync::Arc,
     thread::{spawn, JoinHandle},
 };
 
@@ -7,7 +8,7 @@ use anyhow::{bail, Context, Result};
 use crossbeam::{channel::Sender, select};
 use humantime::Duration;
 
-use crate::{network, shell};
+use crate::{metrics, network, shell};
 
 #[derive(Debug, Clone)]
 pub struct Partition {
@@ -50,14 +51,20 @@ pub(crate) struct Task {
     partition: Partition,
     instances: Vec<network::NamespaceVeth>,
     enabled: HashSet<(network::NamespaceVeth, network::NamespaceVeth)>,
+    metrics: Arc<metrics::Registry>,
 }
 
 impl Task {
-    pub(crate) fn new(partition: Partition, instances: Vec<network::NamespaceVeth>) -> Self {
+    pub(crate) fn new(
+        partition: Partition,
+        instances: Vec<network::NamespaceVeth>,
+        metrics: Arc<metrics::Registry>,
+    ) -> Self {
         Self {
             partition,
             instances,
             enabled: HashSet::new(),
+            metrics,
         }
     }
 
@@ -87,6 +94,7 @@ impl Task {
                 }
             }
         }
+        self.metrics.partition_applied();
         Ok(())
     }
 
@@ -94,6 +102,7 @@ impl Task {
         for (from, to) in self.enabled.drain() {
             shell::drop_packets_revert(&from, &to)?;
         }
+        self.metrics.partition_reverted();
         Ok(())
     }
 }
@@ -0,0 +1,248 @@
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{netlink, network, supervisor, template};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const EXEC_TIMEOUT: Duration = Duration::from_secs(5);
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Probe checks if the instance is in the expected state.
+//
+// supported probes:
+// - tcp:<port> - port is accepting connections on the instance address or loopback in its namespace
+// - http:<port>[/path] - http GET request returns 200, connected the same way as tcp
+// - file:<path> - file exists, relative paths are resolved from the work dir of the instance
+// - log:<regex> - line that matches regex was printed to stdout or stderr
+// - exec:<command> - command exits with 0 when executed with /bin/sh -c the same way as the instance
+//
+// only tcp and http probes can check instances on other hosts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Probe {
+    Tcp(u16),
+    Http { port: u16, path: String },
+    File(PathBuf),
+    Log(Regex),
+    Exec(String),
+}
+
+// Target is the instance that probe checks.
+pub(crate) struct Target<'a> {
+    pub(crate) config: &'a supervisor::CommandConfig,
+    // set by output readers when log probe regex matched a line
+    pub(crate) matched: Option<&'a AtomicBool>,
+}
+
+impl Probe {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (kind, arg) = s
+            .split_once(':')
+            .with_context(|| format!("probe must be in the form of kind:argument, got {}", s))?;
+        Ok(match kind {
+            "tcp" => Probe::Tcp(arg.parse().context("invalid tcp port")?),
+            "http" => {
+                let (port, path) = match arg.find('/') {
+                    Some(i) => (&arg[..i], &arg[i..]),
+                    None => (arg, "/"),
+                };
+                Probe::Http {
+                    port: port.parse().context("invalid http port")?,
+                    path: path.to_string(),
+                }
+            }
+            "file" => Probe::File(PathBuf::from(arg)),
+            "log" => Probe::Log(Regex::new(arg).context("invalid log regex")?),
+            "exec" if !arg.is_empty() => Probe::Exec(arg.to_string()),
+            "exec" => bail!("exec probe requires a command"),
+            _ => bail!(
+                "unknown probe {}. expected one of tcp, http, file, log, exec",
+                kind
+            ),
+        })
+    }
+
+    // render substitutes placeholders in paths and commands
+    pub(crate) fn render(self, context: &template::Context) -> Self {
+        match self {
+            Probe::Http { port, path } => Probe::Http {
+                port,
+                path: context.render(&path),
+            },
+            Probe::File(path) => {
+                Probe::File(PathBuf::from(context.render(&path.to_string_lossy())))
+            }
+            Probe::Exec(command) => Probe::Exec(context.render(&command)),
+            probe => probe,
+        }
+    }
+
+    pub(crate) fn check(&self, target: &Target) -> bool {
+        let config = target.config;
+        let addrs = [config.ip, Ipv4Addr::LOCALHOST];
+        match self {
+            Probe::Tcp(port) => connect(&config.name, &addrs, *port).is_ok(),
+            Probe::Http { port, path } => connect(&config.name, &addrs, *port)
+                .and_then(|stream| http_get(stream, config.ip, *port, path))
+                .unwrap_or(false),
+            Probe::File(path) => config.work_dir.join(path).exists(),
+            Probe::Log(_) => target
+                .matched
+                .is_some_and(|matched| matched.load(Ordering::Relaxed)),
+            Probe::Exec(command) => exec(config, command).unwrap_or_else(|err| {
+                tracing::debug!("exec probe {:?} failed: {:?}", command, err);
+                false
+            }),
+        }
+    }
+
+    pub(crate) fn remote(&self) -> bool {
+        matches!(self, Probe::Tcp(_) | Probe::Http { .. })
+    }
+
+    // check_remote checks instance on another host with address ip from the namespace
+    // of a local instance, as hosts may not have routes to the instances.
+    pub(crate) fn check_remote(&self, namespace: &str, ip: Ipv4Addr) -> bool {
+        match self {
+            Probe::Tcp(port) => connect(namespace, &[ip], *port).is_ok(),
+            Probe::Http { port, path } => connect(namespace, &[ip], *port)
+                .and_then(|stream| http_get(stream, ip, *port, path))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+// exec runs the command the same way as the instance is run, see supervisor::isolate.
+fn exec(config: &supervisor::CommandConfig, command: &str) -> Result<bool> {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg(command)
+        .current_dir(&config.work_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0);
+    supervisor::isolate(&mut cmd, config)?;
+    let mut child = cmd.spawn().context("spawn exec probe")?;
+    let deadline = Instant::now() + EXEC_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.success());
+        }
+        if Instant::now() >= deadline {
+            let _ = signal::killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+            child.wait()?;
+            return Ok(false);
+        }
+        thread::sleep(EXEC_POLL_INTERVAL);
+    }
+}
+
+// connect opens connection from the namespace of the instance, so that probes don't depend on
+// routes and firewall of the host. addresses are tried in order, local instances are also checked
+// on loopback if their address doesn't accept connections, as servers are often bound only to it.
+fn connect(namespace: &str, addrs: &[Ipv4Addr], port: u16) -> Result<TcpStream> {
+    let namespace = network::Namespace {
+        name: namespace.to_string(),
+    };
+    netlink::in_namespace(&namespace, || {
+        let mut result = Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+        for addr in addrs {
+            result = result.or_else(|_| {
+                TcpStream::connect_timeout(&SocketAddr::from((*addr, port)), CONNECT_TIMEOUT)
+            });
+        }
+        result.with_context(|| format!("connect to port {}", port))
+    })
+}
+
+fn http_get(mut stream: TcpStream, ip: Ipv4Addr, port: u16, path: &str) -> Result<bool> {
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+        path, ip, port
+    )?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    Ok(status.split_whitespace().nth(1) == Some("200"))
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Tcp(port) => write!(f, "tcp:{}", port),
+            Probe::Http { port, path } => write!(f, "http:{}{}", port, path),
+            Probe::File(path) => write!(f, "file:{}", path.display()),
+            Probe::Log(regex) => write!(f, "log:{}", regex),
+            Probe::Exec(command) => write!(f, "exec:{}", command),
+        }
+    }
+}
+
+impl TryFrom<String> for Probe {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Probe::parse(&s)
+    }
+}
+
+impl From<Probe> for String {
+    fn from(probe: Probe) -> Self {
+        probe.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(
+            Probe::parse("tcp:7000").unwrap(),
+            Probe::Tcp(7000)
+        ));
+        match Probe::parse("http:8080/health?full=1").unwrap() {
+            Probe::Http { port, path } => {
+                assert_eq!(port, 8080);
+                assert_eq!(path, "/health?full=1");
+            }
+            probe => panic!("unexpected probe {:?}", probe),
+        }
+        assert_eq!(Probe::parse("http:8080").unwrap().to_string(), "http:8080/");
+        assert_eq!(
+            Probe::parse("log:listening on .*:7000")
+                .unwrap()
+                .to_string(),
+            "log:listening on .*:7000"
+        );
+        assert_eq!(
+            Probe::parse("exec:test -f ready").unwrap().to_string(),
+            "exec:test -f ready"
+        );
+        assert!(Probe::parse("exec:").is_err());
+        assert!(Probe::parse("udp:53").is_err());
+        assert!(Probe::parse("tcp").is_err());
+    }
+}
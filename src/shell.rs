@@ -2,6 +2,7 @@
 
 use std::{
     collections::HashMap,
+    path::Path,
     process::{Command, Stdio},
 };
 
@@ -12,9 +13,10 @@ use crate::network;
 
 fn execute(cmd: &str) -> Result<Vec<u8>> {
     tracing::debug!("running: {}", cmd);
-    let mut parts = cmd.split_whitespace();
-    let command = parts.next().unwrap().to_string();
-    let args: Vec<_> = parts.map(|s| s.to_string()).collect();
+    let parts = shlex::split(cmd).ok_or_else(|| anyhow::anyhow!("invalid quoting: {}", cmd))?;
+    let (command, args) = parts
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("empty command"))?;
 
     let execute = Command::new(command)
         .args(args)
@@ -259,3 +261,101 @@ pub(crate) fn vxlan_revert(vxlan: &network::Vxlan) -> Result<()> {
     execute(&format!("ip link del {name}", name = vxlan.name))?;
     Ok(())
 }
+
+pub(crate) fn archive(dir: &Path, entries: &[String], archive: &Path) -> Result<()> {
+    let mut args = vec![
+        "tar".to_string(),
+        "-czf".to_string(),
+        archive.to_string_lossy().to_string(),
+        "-C".to_string(),
+        dir.to_string_lossy().to_string(),
+    ];
+    args.extend(entries.iter().cloned());
+    execute(&shlex::try_join(args.iter().map(String::as_str))?)?;
+    Ok(())
+}
+
+// losetup_attach attaches the image to the first free loop device and returns its path.
+pub(crate) fn losetup_attach(image: &Path) -> Result<String> {
+    let output = execute(&shlex::try_join([
+        "losetup",
+        "--find",
+        "--show",
+        &image.to_string_lossy(),
+    ])?)?;
+    Ok(String::from_utf8(output)?.trim().to_string())
+}
+
+// losetup_find returns loop devices that the image is attached to.
+pub(crate) fn losetup_find(image: &Path) -> Result<Vec<String>> {
+    let output = execute(&shlex::try_join([
+        "losetup",
+        "--noheadings",
+        "--output",
+        "NAME",
+        "--associated",
+        &image.to_string_lossy(),
+    ])?)?;
+    Ok(String::from_utf8(output)?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+pub(crate) fn losetup_detach(device: &str) -> Result<()> {
+    execute(&format!("losetup --detach {}", device))?;
+    Ok(())
+}
+
+pub(crate) fn dmsetup_create(name: &str, table: &str) -> Result<()> {
+    execute(&shlex::try_join(["dmsetup", "create", name, "--table", table])?)?;
+    Ok(())
+}
+
+// dmsetup_reload replaces the table of the device. io is queued while device is suspended.
+pub(crate) fn dmsetup_reload(name: &str, table: &str) -> Result<()> {
+    execute(&format!("dmsetup suspend {}", name))?;
+    let reloaded = execute(&shlex::try_join(["dmsetup", "reload", name, "--table", table])?);
+    // device must be resumed even if reload failed, otherwise io will hang
+    execute(&format!("dmsetup resume {}", name))?;
+    reloaded?;
+    Ok(())
+}
+
+// dmsetup_deps returns names of devices that the device is stacked on, e.g. loop0.
+pub(crate) fn dmsetup_deps(name: &str) -> Result<Vec<String>> {
+    let output = execute(&format!("dmsetup deps -o devname {}", name))?;
+    Ok(parse_deps(&String::from_utf8(output)?))
+}
+
+// parse_deps parses "1 dependencies  : (loop0)"
+fn parse_deps(output: &str) -> Vec<String> {
+    output
+        .split('(')
+        .skip(1)
+        .filter_map(|dep| dep.split_once(')').map(|(name, _)| name.trim().to_string()))
+        .collect()
+}
+
+pub(crate) fn dmsetup_remove(name: &str) -> Result<()> {
+    execute(&format!("dmsetup remove {}", name))?;
+    Ok(())
+}
+
+pub(crate) fn mkfs_ext4(device: &str) -> Result<()> {
+    execute(&format!("mkfs.ext4 -q {}", device))?;
+    Ok(())
+}
+
+pub(crate) fn fallocate(path: &Path, offset: u64, length: u64) -> Result<()> {
+    execute(&shlex::try_join([
+        "fallocate",
+        "--offset",
+        &offset.to_string(),
+        "--length",
+        &length.to_string(),
+        &path.to_string_lossy(),
+    ])?)?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::{fs, io, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+// runtime state of the playground, it is read by other commands (e.g. `play status`)
+// while playground is running. directory is removed when playground is stopped.
+const STATE_DIR: &str = "/run/playground";
+
+pub(crate) fn dir(prefix: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(prefix)
+}
+
+// write replaces the file atomically, so that readers never observe partial content.
+pub(crate) fn write(prefix: &str, name: &str, content: &[u8]) -> Result<()> {
+    let dir = dir(prefix);
+    fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+    let tmp = dir.join(format!(".{}.tmp", name));
+    fs::write(&tmp, content).with_context(|| format!("write {:?}", tmp))?;
+    fs::rename(&tmp, dir.join(name)).with_context(|| format!("rename {:?}", tmp))?;
+    Ok(())
+}
+
+pub(crate) fn read(prefix: &str, name: &str) -> Result<Vec<u8>> {
+    let path = dir(prefix).join(name);
+    fs::read(&path).with_context(|| format!("read {:?}. is playground running?", path))
+}
+
+pub(crate) fn revert(prefix: &str) -> Result<()> {
+    let dir = dir(prefix);
+    match fs::remove_dir_all(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn cleanup(prefix: &str) -> Result<usize> {
+    let entries = match fs::read_dir(STATE_DIR) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).context("read state dir"),
+    };
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(prefix) {
+            fs::remove_dir_all(entry.path())
+                .with_context(|| format!("remove {:?}", entry.path()))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Arc,
+    thread::{spawn, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use crossbeam::{channel::Sender, select};
+
+use crate::{core, metrics, netlink, network, supervisor};
+
+const HEADER: &str = "timestamp,rx_bytes,rx_packets,rx_errors,rx_dropped,tx_bytes,tx_packets,tx_errors,tx_dropped,\
+host_rx_dropped,host_tx_dropped,qdisc_packets,qdisc_drops,qdisc_overlimits,qdisc_backlog,qdisc_qlen";
+
+// Link is a snapshot of counters of the network device.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Link {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+// netlink message types and attributes of a qdisc dump
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const RTM_NEWQDISC: u16 = 36;
+const TCMSG_LEN: usize = 20;
+const TC_H_ROOT: u32 = 0xffff_ffff;
+const TCA_STATS2: u16 = 7;
+const TCA_STATS_BASIC: u16 = 1;
+const TCA_STATS_QUEUE: u16 = 3;
+const TCA_STATS_PKT64: u16 = 8;
+// attribute type without nested and byte order flags
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// Qdisc is a snapshot of counters of queueing disciplines on the device. packets and queue are
+// taken from the root qdisc, drops and overlimits are summed over all of them, so that drops of
+// netem under tbf are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Qdisc {
+    pub packets: u64,
+    pub drops: u64,
+    pub overlimits: u64,
+    // bytes in the queue
+    pub backlog: u64,
+    // packets in the queue
+    pub qlen: u64,
+}
+
+impl Qdisc {
+    // parse adds counters of qdiscs of the device from netlink messages of the dump with seq,
+    // and returns true once the dump is done.
+    pub(crate) fn parse(&mut self, buf: &[u8], seq: u32, ifindex: i32) -> Result<bool> {
+        let mut offset = 0;
+        while offset + NLMSG_HDRLEN <= buf.len() {
+            let len = u32_at(buf, offset) as usize;
+            ensure!(
+                len >= NLMSG_HDRLEN && offset + len <= buf.len(),
+                "truncated netlink message"
+            );
+            let kind = u16_at(buf, offset + 4);
+            let message_seq = u32_at(buf, offset + 8);
+            let message = &buf[offset + NLMSG_HDRLEN..offset + len];
+            offset += align(len);
+            // replies to the previous dump that failed midway
+            if message_seq != seq {
+                continue;
+            }
+            match kind {
+                NLMSG_DONE => return Ok(true),
+                NLMSG_ERROR => {
+                    ensure!(message.len() >= 4, "truncated netlink error");
+                    let errno = -(u32_at(message, 0) as i32);
+                    if errno == 0 {
+                        return Ok(true);
+                    }
+                    return Err(std::io::Error::from_raw_os_error(errno)).context("dump qdisc");
+                }
+                RTM_NEWQDISC => self.add(message, ifindex)?,
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    fn add(&mut self, message: &[u8], ifindex: i32) -> Result<()> {
+        ensure!(message.len() >= TCMSG_LEN, "truncated tcmsg");
+        if u32_at(message, 4) as i32 != ifindex {
+            return Ok(());
+        }
+        let root = u32_at(message, 12) == TC_H_ROOT;
+        let stats = match attributes(&message[TCMSG_LEN..]).find(|(kind, _)| *kind == TCA_STATS2) {
+            Some((_, stats)) => stats,
+            None => return Ok(()),
+        };
+        let (mut packets, mut packets64) = (None, None);
+        for (kind, value) in attributes(stats) {
+            match kind {
+                // bytes as u64 followed by packets as u32
+                TCA_STATS_BASIC if value.len() >= 12 => packets = Some(u32_at(value, 8) as u64),
+                TCA_STATS_PKT64 if value.len() >= 8 => packets64 = Some(u64_at(value, 0)),
+                // qlen, backlog, drops, requeues and overlimits as u32
+                TCA_STATS_QUEUE if value.len() >= 20 => {
+                    self.drops += u32_at(value, 8) as u64;
+                    self.overlimits += u32_at(value, 16) as u64;
+                    if root {
+                        self.qlen = u32_at(value, 0) as u64;
+                        self.backlog = u32_at(value, 4) as u64;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if root {
+            self.packets = packets64.or(packets).unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+// attributes iterates over netlink attributes as type and value.
+fn attributes(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16_at(buf, 0) as usize;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let attribute = (u16_at(buf, 2) & NLA_TYPE_MASK, &buf[4..len]);
+        buf = &buf[align(len).min(buf.len())..];
+        Some(attribute)
+    })
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Sample is taken from the veth pair of the instance. guest counters are from the point of view
+// of the instance, host counters show what was dropped on the bridge side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub timestamp: SystemTime,
+    pub guest: Link,
+    pub host: Link,
+    pub qdisc: Qdisc,
+}
+
+impl Sample {
+    fn row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            humantime::format_rfc3339_millis(self.timestamp),
+            self.guest.rx_bytes,
+            self.guest.rx_packets,
+            self.guest.rx_errors,
+            self.guest.rx_dropped,
+            self.guest.tx_bytes,
+            self.guest.tx_packets,
+            self.guest.tx_errors,
+            self.guest.tx_dropped,
+            self.host.rx_dropped,
+            self.host.tx_dropped,
+            self.qdisc.packets,
+            self.qdisc.drops,
+            self.qdisc.overlimits,
+            self.qdisc.backlog,
+            self.qdisc.qlen,
+        )
+    }
+}
+
+pub(crate) fn path(command: &supervisor::CommandConfig) -> PathBuf {
+    command.work_dir.join(format!("{}.net.csv", command.name))
+}
+
+// Task samples counters of every instance on this host, and exports them to metrics.
+// if csv is enabled, samples are also appended to <work_dir>/<namespace>.net.csv.
+pub(crate) struct Task {
+    interval: Duration,
+    veths: BTreeMap<usize, network::NamespaceVeth>,
+    // sockets in namespaces of instances with netem or tbf, qdisc stats are not collected for others
+    qdisc: BTreeMap<usize, netlink::QdiscSocket>,
+    names: BTreeMap<usize, String>,
+    // empty if csv is disabled
+    paths: BTreeMap<usize, PathBuf>,
+    metrics: Arc<metrics::Registry>,
+}
+
+impl Task {
+    pub(crate) fn new(
+        interval: Duration,
+        commands: &BTreeMap<usize, supervisor::CommandConfig>,
+        data: &core::Data,
+        csv: bool,
+        metrics: Arc<metrics::Registry>,
+    ) -> Result<Self> {
+        let paths: BTreeMap<usize, PathBuf> = commands
+            .iter()
+            .filter(|_| csv)
+            .map(|(index, command)| (*index, path(command)))
+            .collect();
+        // series is started from scratch on every run
+        for path in paths.values() {
+            fs::write(path, format!("{}\n", HEADER))
+                .with_context(|| format!("write {:?}", path))?;
+        }
+        Ok(Self {
+            interval,
+            veths: data
+                .veth
+                .iter()
+                .filter(|(index, _)| commands.contains_key(index))
+                .map(|(index, veth)| (*index, veth.clone()))
+                .collect(),
+            qdisc: data
+                .qdisc
+                .keys()
+                .filter(|index| commands.contains_key(index))
+                .filter_map(|index| data.veth.get(index).map(|veth| (*index, veth)))
+                .map(|(index, veth)| Ok((index, netlink::QdiscSocket::open(veth)?)))
+                .collect::<Result<_>>()?,
+            names: commands
+                .iter()
+                .map(|(index, command)| (*index, command.name.clone()))
+                .collect(),
+            paths,
+            metrics,
+        })
+    }
+
+    fn sample_one(&self, index: usize, veth: &network::NamespaceVeth) -> Result<Sample> {
+        let (guest, host) = netlink::link_stats(veth)?;
+        let qdisc = match self.qdisc.get(&index) {
+            Some(socket) => socket.stats()?,
+            None => Qdisc::default(),
+        };
+        Ok(Sample {
+            timestamp: SystemTime::now(),
+            guest,
+            host,
+            qdisc,
+        })
+    }
+
+    // sample is attempted for every instance, instances that failed are skipped until the next sample.
+    fn sample(&self) {
+        for (index, veth) in self.veths.iter() {
+            let rst = self.sample_one(*index, veth).and_then(|sample| {
+                self.metrics.sample(*index, &self.names[index], sample);
+                let path = match self.paths.get(index) {
+                    Some(path) => path,
+                    None => return Ok(()),
+                };
+                OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(sample.row().as_bytes()))
+                    .with_context(|| format!("write {:?}", path))
+            });
+            if let Err(err) = rst {
+                tracing::warn!("failed to sample net stats of command {}: {:?}", index, err);
+            }
+        }
+    }
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handler: JoinHandle<()>,
+}
+
+impl Background {
+    pub(crate) fn spawn(task: Task) -> Result<Self> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let handle = spawn(move || {
+            loop {
+                task.sample();
+                select! {
+                    recv(receiver) -> _ => break,
+                    default(task.interval) => {},
+                }
+            }
+            tracing::debug!("stopping net stats task");
+            // last sample covers the whole run
+            task.sample();
+        });
+        Ok(Self {
+            sender,
+            handler: handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        _ = self.sender.send(());
+        self.handler.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = ((NLMSG_HDRLEN + payload.len()) as u32)
+            .to_ne_bytes()
+            .to_vec();
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(align(message.len()), 0);
+        message
+    }
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attribute = ((4 + value.len()) as u16).to_ne_bytes().to_vec();
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(value);
+        attribute.resize(align(attribute.len()), 0);
+        attribute
+    }
+
+    fn qdisc(ifindex: i32, parent: u32, packets: u32, queue: [u32; 5]) -> Vec<u8> {
+        let mut tcmsg = vec![0u8; 4];
+        tcmsg.extend_from_slice(&ifindex.to_ne_bytes());
+        tcmsg.extend_from_slice(&0u32.to_ne_bytes());
+        tcmsg.extend_from_slice(&parent.to_ne_bytes());
+        tcmsg.extend_from_slice(&0u32.to_ne_bytes());
+        tcmsg.extend(attribute(1, b"tbf\0"));
+        let mut basic = 1500u64.to_ne_bytes().to_vec();
+        basic.extend_from_slice(&packets.to_ne_bytes());
+        let queue: Vec<u8> = queue.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        let mut stats = attribute(TCA_STATS_BASIC, &basic);
+        stats.extend(attribute(TCA_STATS_QUEUE, &queue));
+        tcmsg.extend(attribute(TCA_STATS2 | 0x8000, &stats));
+        tcmsg
+    }
+
+    #[test]
+    fn test_qdisc_parse() {
+        let mut buf = message(RTM_NEWQDISC, 7, &qdisc(2, TC_H_ROOT, 10, [2, 300, 1, 0, 4]));
+        buf.extend(message(
+            RTM_NEWQDISC,
+            7,
+            &qdisc(2, 0x10001, 10, [0, 0, 3, 0, 0]),
+        ));
+        // loopback and replies to another dump are ignored
+        buf.extend(message(
+            RTM_NEWQDISC,
+            7,
+            &qdisc(1, TC_H_ROOT, 50, [0, 0, 100, 0, 0]),
+        ));
+        buf.extend(message(
+            RTM_NEWQDISC,
+            6,
+            &qdisc(2, TC_H_ROOT, 50, [0, 0, 100, 0, 0]),
+        ));
+
+        let mut sum = Qdisc::default();
+        assert!(!sum.parse(&buf, 7, 2).unwrap());
+        assert!(sum
+            .parse(&message(NLMSG_DONE, 7, &0u32.to_ne_bytes()), 7, 2)
+            .unwrap());
+        assert_eq!(
+            sum,
+            Qdisc {
+                packets: 10,
+                drops: 4,
+                overlimits: 4,
+                backlog: 300,
+                qlen: 2,
+            }
+        );
+
+        let error = message(NLMSG_ERROR, 7, &(-1i32).to_ne_bytes());
+        assert!(Qdisc::default().parse(&error, 7, 2).is_err());
+        assert!(Qdisc::default().parse(&buf[..20], 7, 2).is_err());
+    }
+
+    #[test]
+    fn test_row() {
+        let sample = Sample {
+            timestamp: SystemTime::UNIX_EPOCH,
+            guest: Link {
+                rx_bytes: 100,
+                rx_packets: 2,
+                tx_bytes: 50,
+                tx_packets: 1,
+                ..Default::default()
+            },
+            host: Link {
+                rx_dropped: 7,
+                ..Default::default()
+            },
+            qdisc: Qdisc {
+                drops: 3,
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            sample.row(),
+            "1970-01-01T00:00:00.000Z,100,2,0,0,50,1,0,0,7,0,0,3,0,0,0\n"
+        );
+        assert_eq!(HEADER.split(',').count(), sample.row().split(',').count());
+    }
+}
@@ -1,59 +1,174 @@
 use std::{
-    collections::BTreeMap,
-    fs::OpenOptions,
-    io::{BufRead, BufReader},
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{BufRead, BufReader, Read},
+    net::Ipv4Addr,
     ops::Range,
+    os::unix::{fs::MetadataExt, process::CommandExt},
     path::PathBuf,
-    process::{Child, Command, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use crossbeam::channel::Sender;
+use nix::{
+    errno::Errno,
+    sys::{
+        resource::{getrlimit, setrlimit, Resource},
+        signal,
+    },
+    unistd::Pid,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::network;
+use crate::{cgroup, clock, data, exec, logs, netlink, network, probe, template, trigger, user};
+
+pub use nix::sys::signal::Signal;
+
+// how often to check if commands exited while waiting for the grace period
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// how long to wait for leftover processes in the namespace to exit after SIGKILL
+const NAMESPACE_KILL_TIMEOUT: Duration = Duration::from_secs(1);
+// how long to wait for output of stopped commands to be read
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(1);
+// open files in this process per command: stdout and stderr pipes and combined log.
+// aggregated log is shared by all commands
+const FILES_PER_COMMAND: u64 = 3;
+// how often to check readiness probes
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Instance describes a single command instance that should be generated.
+// command and environment values are templates, see template::Context.
+#[derive(Debug, Clone, Default)]
+pub struct Instance {
+    pub command: String,
+    pub env: BTreeMap<String, String>,
+    pub work_dir: PathBuf,
+    // hostname of the instance. if not provided, name of the namespace is used.
+    pub hostname: Option<String>,
+    // name of the command that instance belongs to, and the port it serves on
+    pub group: Option<String>,
+    pub port: Option<u16>,
+    // instance is considered ready when probe succeeds
+    pub ready: Option<probe::Probe>,
+    // groups that must be ready before instance is started
+    pub after: Vec<String>,
+    // instance is checked periodically with this probe after it was started
+    pub health: Option<probe::Probe>,
+    pub limits: cgroup::Limits,
+    // instance is launched in a time namespace with the clock offset
+    pub clock_offset: Option<clock::Offset>,
+    // command is executed as this user, if not provided it runs as root
+    pub user: Option<user::Credentials>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandConfig {
     pub name: String,
+    pub hostname: String,
+    pub group: Option<String>,
+    pub port: Option<u16>,
+    pub ip: Ipv4Addr,
+    pub ready: Option<probe::Probe>,
+    pub after: Vec<String>,
+    pub health: Option<probe::Probe>,
+    pub limits: cgroup::Limits,
+    // cgroup of the instance, if cgroups are available on the host
+    pub cgroup: Option<PathBuf>,
+    // offset of monotonic and boottime clocks in nanoseconds, random offset is resolved once per instance
+    pub clock_offset: Option<i64>,
+    // private data directory of the instance
+    pub data: Option<data::Mount>,
+    pub user: Option<user::Credentials>,
     pub command: String,
     pub work_dir: PathBuf,
     pub os_env: Option<BTreeMap<String, String>>,
     pub redirect: bool,
+    // rotation of the combined log, if output is redirected
+    pub log_rotation: logs::Rotation,
+    // output of every instance is also written to the aggregated log, if set
+    #[serde(skip)]
+    pub(crate) json_log: Option<Arc<logs::Json>>,
+    // output of every instance is matched against triggers, if any
+    #[serde(skip)]
+    pub(crate) triggers: Option<trigger::Matcher>,
+    // run command with /bin/sh -c instead of splitting it into arguments
+    pub shell: bool,
 }
 
 #[derive(Debug)]
 pub struct Execution {
+    // name of the network namespace the command is running in
+    pub name: String,
+    // command is the leader of its own process group
     pub child: Child,
     pub stdout_handler: Option<JoinHandle<()>>,
     pub stderr_handler: Option<JoinHandle<()>>,
+    // set when output matched log readiness probe
+    pub log_matched: Option<Arc<AtomicBool>>,
 }
 
 pub fn generate(
     prefix: &str,
     redirect: bool,
+    shell: bool,
     hosts: impl Iterator<Item = Range<usize>>,
-    mut commands: impl Iterator<Item = String>,
-    mut env: impl Iterator<Item = BTreeMap<String, String>>,
-    mut workdir: impl Iterator<Item = PathBuf>,
+    mut instances: impl Iterator<Item = Instance>,
+    contexts: &BTreeMap<usize, template::Context>,
 ) -> Result<Vec<BTreeMap<usize, CommandConfig>>> {
     // split commands into equal chunks with all remaining commands in the last chunk
     hosts
         .map(|chunk| {
             chunk
-                .zip(&mut commands)
-                .map(|(index, command)| {
-                    let work_dir = workdir.next().ok_or_else(|| {
-                        anyhow::anyhow!("workdir is not provided for command {}", index)
+                .zip(&mut instances)
+                .map(|(index, instance)| {
+                    let context = contexts.get(&index).ok_or_else(|| {
+                        anyhow::anyhow!("template context is missing for command {}", index)
                     })?;
-                    let os_env = env.next();
+                    let os_env = if instance.env.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            instance
+                                .env
+                                .into_iter()
+                                .map(|(key, value)| (key, context.render(&value)))
+                                .collect(),
+                        )
+                    };
+                    let name = network::Namespace::name(prefix, index);
                     let command = CommandConfig {
-                        name: network::Namespace::name(prefix, index),
-                        command,
-                        work_dir,
+                        hostname: instance.hostname.unwrap_or_else(|| name.clone()),
+                        name,
+                        group: instance.group,
+                        port: instance.port,
+                        ip: context.ip,
+                        ready: instance.ready.map(|ready| ready.render(context)),
+                        after: instance.after,
+                        health: instance.health.map(|health| health.render(context)),
+                        limits: instance.limits,
+                        cgroup: None,
+                        clock_offset: instance.clock_offset.map(|offset| offset.resolve()),
+                        data: None,
+                        user: instance.user,
+                        command: context.render(&instance.command),
+                        // paths derived from work_dir are used after command changed its directory,
+                        // e.g. source of the data mount, so it must not be relative
+                        work_dir: std::path::absolute(&instance.work_dir)
+                            .with_context(|| format!("resolve work dir {:?}", instance.work_dir))?,
                         os_env,
                         redirect,
+                        log_rotation: logs::Rotation::default(),
+                        json_log: None,
+                        triggers: None,
+                        shell,
                     };
                     Ok((index, command))
                 })
@@ -62,160 +177,718 @@ pub fn generate(
         .collect()
 }
 
+// launch starts commands in the order of their dependencies.
+// command is started only after all instances of the groups it depends on are ready. instances on
+// other hosts, provided in remote, are checked from this host once nothing else can be started.
 pub fn launch(
     cfg: &BTreeMap<usize, CommandConfig>,
+    remote: &BTreeMap<usize, CommandConfig>,
     execution: &mut BTreeMap<usize, Execution>,
     errors: &Sender<Result<()>>,
+    ready_timeout: Duration,
+    interrupted: &AtomicBool,
 ) -> Result<()> {
-    for (index, command) in cfg {
-        let (child, stdout_handler, stderr_handler) = launch_one(
-            *index,
-            &command.name,
-            &command.command,
-            &command.work_dir,
-            &command.os_env,
-            command.redirect,
-            errors,
-        )?;
-        let command = Execution {
-            child,
-            stdout_handler,
-            stderr_handler,
-        };
-        execution.insert(*index, command);
+    let local: BTreeSet<&str> = cfg.values().filter_map(|c| c.group.as_deref()).collect();
+    // groups whose local instances are ready, but instances on other hosts may not be
+    let mut local_ready: BTreeSet<&str> = BTreeSet::new();
+    let mut ready: BTreeSet<&str> = BTreeSet::new();
+    let mut pending: BTreeSet<usize> = cfg.keys().copied().collect();
+    while !pending.is_empty() {
+        let wave: Vec<usize> = pending
+            .iter()
+            .copied()
+            .filter(|index| {
+                cfg[index]
+                    .after
+                    .iter()
+                    .all(|after| ready.contains(after.as_str()))
+            })
+            .collect();
+        if wave.is_empty() {
+            let blocking: BTreeSet<&str> = pending
+                .iter()
+                .flat_map(|index| cfg[index].after.iter().map(|after| after.as_str()))
+                .filter(|after| !ready.contains(after))
+                .filter(|after| local_ready.contains(after) || !local.contains(after))
+                .collect();
+            if blocking.is_empty() {
+                anyhow::bail!(
+                    "commands {:?} can't be started because of cyclic dependencies",
+                    pending
+                );
+            }
+            // groups are waited until any of them is ready, as instances of other groups
+            // on other hosts may depend on commands that are unblocked by it
+            let groups = wait_remote(cfg, remote, &blocking, ready_timeout, interrupted)?;
+            tracing::info!("commands {:?} are ready on other hosts", groups);
+            ready.extend(groups);
+            continue;
+        }
+        for index in wave.iter() {
+            pending.remove(index);
+            execution.insert(*index, launch_one(*index, &cfg[index], errors)?);
+        }
+        wait_ready(cfg, execution, &wave, ready_timeout, interrupted)?;
+        let groups: BTreeSet<&str> = wave
+            .iter()
+            .filter_map(|index| cfg[index].group.as_deref())
+            .collect();
+        if !groups.is_empty() {
+            tracing::info!("commands {:?} are ready", groups);
+        }
+        for group in groups {
+            if remote.values().any(|c| c.group.as_deref() == Some(group)) {
+                local_ready.insert(group);
+            } else {
+                ready.insert(group);
+            }
+        }
+    }
+    Ok(())
+}
+
+// wait_remote waits until all instances of at least one of the groups on other hosts are ready,
+// and returns groups that are ready. instances are probed from the namespace of a local instance.
+fn wait_remote<'a>(
+    cfg: &BTreeMap<usize, CommandConfig>,
+    remote: &BTreeMap<usize, CommandConfig>,
+    groups: &BTreeSet<&'a str>,
+    timeout: Duration,
+    interrupted: &AtomicBool,
+) -> Result<BTreeSet<&'a str>> {
+    let namespace = match cfg.values().next() {
+        Some(command) => command.name.as_str(),
+        None => return Ok(groups.clone()),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut pending: BTreeMap<&str, Vec<&CommandConfig>> = groups
+        .iter()
+        .map(|group| {
+            let instances = remote
+                .values()
+                .filter(|c| c.group.as_deref() == Some(*group) && c.ready.is_some())
+                .collect();
+            (*group, instances)
+        })
+        .collect();
+    loop {
+        for instances in pending.values_mut() {
+            instances.retain(
+                |c| !matches!(&c.ready, Some(ready) if ready.check_remote(namespace, c.ip)),
+            );
+        }
+        let ready: BTreeSet<&str> = groups
+            .iter()
+            .copied()
+            .filter(|group| pending[group].is_empty())
+            .collect();
+        if !ready.is_empty() {
+            return Ok(ready);
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!(
+                "commands {:?} on other hosts are not ready after {:?}",
+                groups,
+                timeout
+            );
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            anyhow::bail!(
+                "interrupted while waiting for commands {:?} on other hosts to be ready",
+                groups
+            );
+        }
+        thread::sleep(READY_POLL_INTERVAL);
+    }
+}
+
+fn wait_ready(
+    cfg: &BTreeMap<usize, CommandConfig>,
+    execution: &mut BTreeMap<usize, Execution>,
+    wave: &[usize],
+    timeout: Duration,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut pending: Vec<usize> = wave
+        .iter()
+        .copied()
+        .filter(|index| cfg[index].ready.is_some())
+        .collect();
+    loop {
+        for index in pending.iter() {
+            let command = execution
+                .get_mut(index)
+                .expect("launched command must exist");
+            if let Some(status) = command.child.try_wait()? {
+                anyhow::bail!("command {} exited before it was ready: {}", index, status);
+            }
+        }
+        pending.retain(|index| !is_ready(&cfg[index], &execution[index]));
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("commands {:?} are not ready after {:?}", pending, timeout);
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            anyhow::bail!(
+                "interrupted while waiting for commands {:?} to be ready",
+                pending
+            );
+        }
+        thread::sleep(READY_POLL_INTERVAL);
+    }
+}
+
+fn is_ready(config: &CommandConfig, execution: &Execution) -> bool {
+    match &config.ready {
+        Some(ready) => check(ready, config, execution.log_matched.as_deref()),
+        None => true,
+    }
+}
+
+// check runs the probe against the running instance of the command.
+// matched is set when output of the instance matched log probe.
+pub(crate) fn check(
+    probe: &probe::Probe,
+    config: &CommandConfig,
+    matched: Option<&AtomicBool>,
+) -> bool {
+    probe.check(&probe::Target { config, matched })
+}
+
+// isolate makes cmd run the same way as the instance of the command: in its namespaces and cgroup,
+// with its data mount, clock offset, user and environment.
+pub(crate) fn isolate(cmd: &mut Command, config: &CommandConfig) -> Result<()> {
+    let mut hook = exec::Hook::new(
+        &network::Namespace {
+            name: config.name.clone(),
+        },
+        &config.hostname,
+    )?;
+    if let Some(path) = &config.cgroup {
+        hook = hook.with_cgroup(cgroup::procs(path)?);
+    }
+    if let Some(data) = &config.data {
+        hook = hook.with_bind(&data.source, &data.target)?;
+    }
+    if let Some(offset) = config.clock_offset {
+        hook = hook.with_clock_offset(offset);
+    }
+    if let Some(user) = &config.user {
+        hook = hook.with_user(user);
+        cmd.env("USER", &user.name)
+            .env("LOGNAME", &user.name)
+            .env("HOME", &user.home);
+    }
+    // SAFETY: hook doesn't allocate and makes only syscalls that are safe to use after fork
+    unsafe {
+        cmd.pre_exec(move || hook.run());
+    }
+    if let Some(os_env) = &config.os_env {
+        cmd.envs(os_env);
     }
     Ok(())
 }
 
-pub fn stop(execution: &mut BTreeMap<usize, Execution>) -> Result<()> {
+// raise_nofile_limit raises soft limit of open files to the hard limit. output of every command
+// is read by this process, and the default soft limit of 1024 is reached with a few hundred commands.
+pub(crate) fn raise_nofile_limit(commands: usize) -> Result<()> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).context("get open files limit")?;
+    if soft < hard {
+        setrlimit(Resource::RLIMIT_NOFILE, hard, hard).context("raise open files limit")?;
+    }
+    let required = commands as u64 * FILES_PER_COMMAND;
+    if required > hard {
+        tracing::warn!(
+            "{} commands may need up to {} open files, but the limit is {}",
+            commands,
+            required,
+            hard
+        );
+    }
+    Ok(())
+}
+
+// parse signal name (TERM, SIGTERM) or number (15)
+pub fn parse_signal(s: &str) -> Result<Signal> {
+    if let Ok(number) = s.parse::<i32>() {
+        return Signal::try_from(number)
+            .with_context(|| format!("invalid signal number {}", number));
+    }
+    let name = s.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).with_context(|| format!("invalid signal {}", s))
+}
+
+// stop sends the signal to the process group of every command and waits for all of them
+// to exit within the timeout. process groups that are still running after the timeout are killed,
+// and any process that is left in the network namespace is killed as well.
+pub fn stop(
+    execution: &mut BTreeMap<usize, Execution>,
+    signal: Signal,
+    timeout: Duration,
+) -> Result<()> {
+    let outputs = shutdown(execution, signal, timeout);
+    join_outputs(outputs);
+    Ok(())
+}
+
+// shutdown stops commands and returns readers of their output.
+fn shutdown(
+    execution: &mut BTreeMap<usize, Execution>,
+    signal: Signal,
+    timeout: Duration,
+) -> Vec<(String, JoinHandle<()>)> {
+    // commands whose leader exited before stop, but other processes in their namespace survived it
+    let mut orphaned: BTreeSet<usize> = BTreeSet::new();
+    for (index, command) in execution.iter_mut() {
+        match terminate(command, signal) {
+            Ok(true) => {
+                orphaned.insert(*index);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!("failed to send {} to command {}: {:?}", signal, index, err);
+            }
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut pending: BTreeSet<usize> = execution.keys().copied().collect();
+    loop {
+        pending.retain(|index| {
+            let command = execution
+                .get_mut(index)
+                .expect("pending command must exist");
+            match command.child.try_wait() {
+                Ok(Some(status)) => {
+                    if let Err(err) = report(status) {
+                        tracing::error!("command {}: {:?}", index, err);
+                    }
+                    false
+                }
+                Ok(None) => true,
+                Err(err) => {
+                    tracing::error!("failed to wait for command {}: {:?}", index, err);
+                    false
+                }
+            }
+        });
+        orphaned.retain(|index| match namespace_pids(&execution[index].name) {
+            Ok(pids) => !pids.is_empty(),
+            Err(err) => {
+                tracing::error!("command {}: {:?}", index, err);
+                false
+            }
+        });
+        if (pending.is_empty() && orphaned.is_empty()) || Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+
+    for index in pending.union(&orphaned) {
+        tracing::warn!(
+            "command {} didn't exit within {:?}, killing",
+            index,
+            timeout
+        );
+    }
+    // groups of exited leaders are not killed, as group id may be reused after the leader was reaped.
+    // processes left in such groups are killed by namespace below
     for (index, command) in execution.iter_mut() {
         if let Err(err) = kill(&mut command.child) {
             tracing::error!("failed to kill command {}: {:?}", index, err);
         }
     }
-    for (index, command) in execution.iter_mut() {
+    for index in pending {
+        let command = execution
+            .get_mut(&index)
+            .expect("pending command must exist");
         if let Err(err) = wait(&mut command.child) {
             tracing::error!("failed to wait for command {}: {:?}", index, err);
         }
     }
-    execution.clear();
+    for (index, command) in execution.iter() {
+        if let Err(err) = ensure_namespace_empty(&command.name) {
+            tracing::error!("command {}: {:?}", index, err);
+        }
+    }
+    let mut outputs = vec![];
+    for (_, mut command) in std::mem::take(execution) {
+        for handler in [command.stdout_handler.take(), command.stderr_handler.take()]
+            .into_iter()
+            .flatten()
+        {
+            outputs.push((command.name.clone(), handler));
+        }
+    }
+    outputs
+}
+
+// join_outputs waits until output of stopped commands is read, so that logs are complete after it.
+// pipes may be held open by a process that left the namespace, such readers are not waited for.
+fn join_outputs(outputs: Vec<(String, JoinHandle<()>)>) {
+    let deadline = Instant::now() + OUTPUT_TIMEOUT;
+    while outputs.iter().any(|(_, handler)| !handler.is_finished()) && Instant::now() < deadline {
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+    let mut open = BTreeSet::new();
+    for (name, handler) in outputs {
+        if handler.is_finished() {
+            _ = handler.join();
+        } else {
+            open.insert(name);
+        }
+    }
+    for name in open {
+        tracing::warn!(
+            "output of command {} is still open after {:?}, not waiting for it",
+            name,
+            OUTPUT_TIMEOUT
+        );
+    }
+}
+
+// restart stops a single command the same way as on shutdown and launches it again.
+pub(crate) fn restart(
+    config: &CommandConfig,
+    tasks: &Mutex<BTreeMap<usize, Execution>>,
+    index: usize,
+    errors: &Sender<Result<()>>,
+    signal: Signal,
+    timeout: Duration,
+) -> Result<()> {
+    thaw(config, tasks, index)?;
+    stop_one(tasks, index, signal, timeout)?;
+    start_one(config, &mut tasks.lock().unwrap(), index, errors)
+}
+
+// thaw resumes the instance paused by a fault, paused processes don't handle the stop signal.
+fn thaw(
+    config: &CommandConfig,
+    tasks: &Mutex<BTreeMap<usize, Execution>>,
+    index: usize,
+) -> Result<()> {
+    if let Some(path) = &config.cgroup {
+        return cgroup::write(path, "cgroup.freeze", "0");
+    }
+    let tasks = tasks.lock().unwrap();
+    let execution = match tasks.get(&index) {
+        Some(execution) => execution,
+        None => return Ok(()),
+    };
+    match signal::killpg(Pid::from_raw(execution.child.id() as i32), Signal::SIGCONT) {
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("resume command {}", index)),
+    }
+}
+
+// stop_one stops a single command the same way as on shutdown, and removes it from execution.
+// returns false if command is not running. output of the command is read after the lock is released.
+pub(crate) fn stop_one(
+    tasks: &Mutex<BTreeMap<usize, Execution>>,
+    index: usize,
+    signal: Signal,
+    timeout: Duration,
+) -> Result<bool> {
+    let outputs = {
+        let mut execution = tasks.lock().unwrap();
+        let command = match execution.remove(&index) {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+        shutdown(&mut BTreeMap::from([(index, command)]), signal, timeout)
+    };
+    join_outputs(outputs);
+    Ok(true)
+}
+
+// start_one launches a single command in its namespace again, e.g. after it was stopped with stop_one.
+// command that is already running is left as is, e.g. if it was restarted by health check during kill fault.
+pub(crate) fn start_one(
+    config: &CommandConfig,
+    execution: &mut BTreeMap<usize, Execution>,
+    index: usize,
+    errors: &Sender<Result<()>>,
+) -> Result<()> {
+    if execution.contains_key(&index) {
+        tracing::warn!(
+            "command {} is already running, not starting it again",
+            index
+        );
+        return Ok(());
+    }
+    // instance that was paused is started in the same cgroup, it would be frozen right away
+    if let Some(path) = &config.cgroup {
+        cgroup::write(path, "cgroup.freeze", "0")?;
+    }
+    execution.insert(index, launch_one(index, config, errors)?);
     Ok(())
 }
 
+// terminate signals the group of the command. if its leader was already reaped, group id may be
+// reused, so processes that survived the leader are found in the namespace and signaled one by one.
+// returns true if any of such processes were signaled.
+fn terminate(command: &mut Execution, sig: Signal) -> Result<bool> {
+    if command.child.try_wait()?.is_some() {
+        let pids = namespace_pids(&command.name)?;
+        for pid in pids.iter() {
+            match signal::kill(*pid, sig) {
+                Ok(()) | Err(Errno::ESRCH) => {}
+                Err(err) => return Err(err).with_context(|| format!("signal process {}", pid)),
+            }
+        }
+        return Ok(!pids.is_empty());
+    }
+    match signal::killpg(Pid::from_raw(command.child.id() as i32), sig) {
+        // whole group already exited
+        Ok(()) | Err(Errno::ESRCH) => Ok(false),
+        Err(err) => Err(err).context("send signal"),
+    }
+}
+
 fn kill(process: &mut Child) -> Result<()> {
-    process.kill().context("kill process")?;
-    Ok(())
+    if process.try_wait()?.is_some() {
+        return Ok(());
+    }
+    match signal::killpg(Pid::from_raw(process.id() as i32), Signal::SIGKILL) {
+        // whole group already exited
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(err) => Err(err).context("kill process group"),
+    }
 }
 
 fn wait(process: &mut Child) -> Result<()> {
     match process.wait() {
-        Ok(status) if status.code().is_none() => {
-            tracing::debug!("command was terminated by signal: {}", status);
-        }
-        Ok(status) => {
-            if !status.success() {
-                anyhow::bail!("command failed with status: {}", status);
-            }
-        }
+        Ok(status) => report(status),
         Err(err) => {
             anyhow::bail!("failed to wait for command: {:?}", err);
         }
     }
-    // for handler in task.output_handlers.drain(..) {
-    //     _ = handler.join();
-    // }
+}
+
+fn report(status: ExitStatus) -> Result<()> {
+    if status.code().is_none() {
+        tracing::debug!("command was terminated by signal: {}", status);
+    } else if !status.success() {
+        anyhow::bail!("command failed with status: {}", status);
+    }
     Ok(())
 }
 
+// ensure_namespace_empty kills processes that escaped process group of the command
+// (for example by calling setsid) but are still running in its network namespace.
+fn ensure_namespace_empty(name: &str) -> Result<()> {
+    let pids = namespace_pids(name)?;
+    if pids.is_empty() {
+        return Ok(());
+    }
+    tracing::warn!(
+        "killing {} leftover processes in namespace {}",
+        pids.len(),
+        name
+    );
+    for pid in pids {
+        match signal::kill(pid, Signal::SIGKILL) {
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(err) => tracing::error!("failed to kill process {}: {:?}", pid, err),
+        }
+    }
+    let deadline = Instant::now() + NAMESPACE_KILL_TIMEOUT;
+    loop {
+        let pids = namespace_pids(name)?;
+        if pids.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("namespace {} is not empty. processes {:?}", name, pids);
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+}
+
+// namespace_pids finds all processes that run in the named network namespace
+// by comparing inodes of /proc/<pid>/ns/net with the inode of the namespace mount.
+fn namespace_pids(name: &str) -> Result<Vec<Pid>> {
+    let path = netlink::ns_path(&network::Namespace {
+        name: name.to_string(),
+    });
+    let ns = match fs::metadata(&path) {
+        Ok(ns) => ns,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("stat {}", path)),
+    };
+    let mut pids = vec![];
+    for entry in fs::read_dir("/proc").context("read /proc")? {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|f| f.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // process may exit at any point, such errors are not interesting
+        if let Ok(net) = fs::metadata(entry.path().join("ns/net")) {
+            if net.ino() == ns.ino() && net.dev() == ns.dev() {
+                pids.push(Pid::from_raw(pid));
+            }
+        }
+    }
+    Ok(pids)
+}
+
 fn launch_one(
     index: usize,
-    name: &str,
-    cmd: &str,
-    work_dir: &PathBuf,
-    os_env: &Option<BTreeMap<String, String>>,
-    redirect: bool,
+    config: &CommandConfig,
     errors: &Sender<Result<()>>,
-) -> anyhow::Result<(Child, Option<JoinHandle<()>>, Option<JoinHandle<()>>)> {
-    let cmd = cmd.replace("{index}", &index.to_string());
-    let cmd = format!("ip netns exec {} {}", name, cmd);
+) -> anyhow::Result<Execution> {
+    let name = config.name.as_str();
+    let work_dir = &config.work_dir;
+    let redirect = config.redirect;
+    let cmd = &config.command;
 
-    tracing::debug!(redirect = redirect, "running command: {}", cmd);
+    tracing::debug!(
+        redirect = redirect,
+        shell = config.shell,
+        namespace = name,
+        "running command: {}",
+        cmd
+    );
 
-    let mut splitted = cmd.split_whitespace();
-    let first = splitted
-        .next()
+    let args = if config.shell {
+        vec!["/bin/sh".to_string(), "-c".to_string(), cmd.to_string()]
+    } else {
+        shlex::split(cmd)
+            .ok_or_else(|| anyhow::anyhow!("invalid quoting in the command string: {}", cmd))?
+    };
+    let (first, rest) = args
+        .split_first()
         .ok_or_else(|| anyhow::anyhow!("no command found in the command string: {}", cmd))?;
 
     let mut shell = Command::new(first);
-    shell.args(splitted);
+    shell.args(rest);
     shell.current_dir(&work_dir);
-    if !redirect {
-        shell.stdout(Stdio::piped()).stderr(Stdio::piped());
-    } else {
-        let stdout = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(work_dir.join(format!("{}.stdout", name)))?;
-        let stderr = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(work_dir.join(format!("{}.stderr", name)))?;
-        shell.stdout(stdout).stderr(stderr);
-    }
-
-    if let Some(os_env) = os_env {
-        for (key, value) in os_env {
-            shell.env(key, value);
-        }
+    // every command gets its own process group, so that the whole tree can be signaled on stop
+    shell.process_group(0);
+    if let Some(offset) = config.clock_offset {
+        tracing::debug!(namespace = name, "clock offset {}ns", offset);
     }
+    isolate(&mut shell, config)?;
+    shell.stdout(Stdio::piped()).stderr(Stdio::piped());
 
+    // sink is opened before spawn, so that the command isn't left running if it can't be opened
+    let sink = if redirect {
+        Some(Arc::new(Mutex::new(logs::Sink::open(
+            logs::path(work_dir, name),
+            config.log_rotation.clone(),
+            config.user.clone(),
+        )?)))
+    } else {
+        None
+    };
     let mut shell = shell.spawn().context("failed to spawn command")?;
-    let handlers = if !redirect {
-        let stdout = shell
-            .stdout
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("failed to take stdout from child process"))?;
-
-        let stderr = shell
-            .stderr
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("failed to take stderr from child process"))?;
-
-        let id = name.to_string();
-        let sender = errors.clone();
-        let stdout_handler = thread::spawn(move || {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => {
-                        tracing::info!("[{}]: {}", id, line);
+    let matcher = match &config.ready {
+        Some(probe::Probe::Log(regex)) => Some((regex.clone(), Arc::new(AtomicBool::new(false)))),
+        _ => None,
+    };
+    let stdout = shell
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("failed to take stdout from child process"))?;
+
+    let stderr = shell
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("failed to take stderr from child process"))?;
+
+    let outputs = Outputs {
+        index,
+        matcher,
+        sink,
+        json: config.json_log.clone(),
+        triggers: config.triggers.clone(),
+    };
+    let stdout_handler = read_output(name, "stdout", stdout, errors, outputs.clone());
+    let stderr_handler = read_output(name, "stderr", stderr, errors, outputs.clone());
+    Ok(Execution {
+        name: name.to_string(),
+        child: shell,
+        stdout_handler: Some(stdout_handler),
+        stderr_handler: Some(stderr_handler),
+        log_matched: outputs.matcher.map(|(_, matched)| matched),
+    })
+}
+
+// Outputs are shared by stdout and stderr readers of the instance.
+#[derive(Clone)]
+struct Outputs {
+    index: usize,
+    // log readiness probe
+    matcher: Option<(regex::Regex, Arc<AtomicBool>)>,
+    sink: Option<Arc<Mutex<logs::Sink>>>,
+    json: Option<Arc<logs::Json>>,
+    triggers: Option<trigger::Matcher>,
+}
+
+fn read_output(
+    name: &str,
+    stream: &'static str,
+    output: impl Read + Send + 'static,
+    errors: &Sender<Result<()>>,
+    outputs: Outputs,
+) -> JoinHandle<()> {
+    let id = name.to_string();
+    let sender = errors.clone();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            // output isn't required to be utf-8, invalid sequences are replaced
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => return,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf);
+                    let line = line.strip_suffix('\n').unwrap_or(&line);
+                    let line = line.strip_suffix('\r').unwrap_or(line);
+                    if let Some((regex, matched)) = &outputs.matcher {
+                        if regex.is_match(line) {
+                            matched.store(true, Ordering::Relaxed);
+                        }
                     }
-                    Err(e) => {
-                        let _ = sender.send(Err(e).context("stdout"));
-                        return;
+                    match &outputs.sink {
+                        Some(sink) => {
+                            if let Err(err) = sink.lock().unwrap().write(stream, line) {
+                                let _ = sender.send(Err(err).context(stream));
+                                return;
+                            }
+                        }
+                        None => tracing::info!("[{}]: {}", id, line),
                     }
-                }
-            }
-        });
-        let id = name.to_string();
-        let sender = errors.clone();
-        let stderr_handler = thread::spawn(move || {
-            let reader = BufReader::new(stderr);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => {
-                        tracing::info!("[{}]: {}", id, line);
+                    if let Some(json) = &outputs.json {
+                        if let Err(err) = json.write(outputs.index, &id, stream, line) {
+                            let _ = sender.send(Err(err).context(stream));
+                            return;
+                        }
                     }
-                    Err(e) => {
-                        let _ = sender.send(Err(e).context("stderr"));
-                        return;
+                    if let Some(triggers) = &outputs.triggers {
+                        triggers.check(outputs.index, line);
                     }
                 }
+                Err(e) => {
+                    let _ = sender.send(Err(e).context(stream));
+                    return;
+                }
             }
-        });
-        (Some(stdout_handler), Some(stderr_handler))
-    } else {
-        (None, None)
-    };
-    Ok((shell, handlers.0, handlers.1))
+        }
+    })
 }
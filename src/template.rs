@@ -0,0 +1,172 @@
+use std::{collections::BTreeMap, net::Ipv4Addr};
+
+use anyhow::{Context as _, Result};
+
+use crate::core;
+
+// Context holds values that can be substituted into a command template.
+//
+// supported placeholders:
+// - {index} - index of the instance
+// - {ip} - ip address of the instance
+// - {peers} - comma separated ip addresses of all instances on all hosts, ordered by index
+// - {bridge_ip} - address of the bridge that instance is connected to, it is reachable from the host
+// - {host} - identifier of the host that runs the instance
+// - {total} - total number of instances on all hosts
+//
+// addresses can be joined with a port, {ip:7000} or {peers:7000}.
+// placeholders that are not recognized are left as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    pub index: usize,
+    pub ip: Ipv4Addr,
+    pub peers: Vec<Ipv4Addr>,
+    pub bridge_ip: Ipv4Addr,
+    pub host: usize,
+    pub total: usize,
+}
+
+impl Context {
+    pub fn render(&self, template: &str) -> String {
+        let mut rst = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rst.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let replaced = rest
+                .find('}')
+                .and_then(|end| self.lookup(&rest[1..end]).map(|value| (end, value)));
+            match replaced {
+                Some((end, value)) => {
+                    rst.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    rst.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rst.push_str(rest);
+        rst
+    }
+
+    fn lookup(&self, placeholder: &str) -> Option<String> {
+        let (key, port) = match placeholder.split_once(':') {
+            Some((key, port)) => (key, Some(port.parse::<u16>().ok()?)),
+            None => (placeholder, None),
+        };
+        let with_port = |ip: &Ipv4Addr| match port {
+            Some(port) => format!("{}:{}", ip, port),
+            None => ip.to_string(),
+        };
+        match (key, port) {
+            ("index", None) => Some(self.index.to_string()),
+            ("host", None) => Some(self.host.to_string()),
+            ("total", None) => Some(self.total.to_string()),
+            ("ip", _) => Some(with_port(&self.ip)),
+            ("bridge_ip", _) => Some(with_port(&self.bridge_ip)),
+            ("peers", _) => Some(
+                self.peers
+                    .iter()
+                    .map(with_port)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            _ => None,
+        }
+    }
+}
+
+// default_env returns variables that expose template context to every instance.
+// values are templates and must be rendered for each instance.
+// PLAY_PEERS is left out if peers is false. a single variable is limited to 128KiB,
+// so it doesn't fit addresses of more than about 11k instances.
+pub fn default_env(peers: bool) -> BTreeMap<String, String> {
+    [
+        ("PLAY_INDEX", "{index}"),
+        ("PLAY_IP", "{ip}"),
+        ("PLAY_PEERS", "{peers}"),
+        ("PLAY_BRIDGE_IP", "{bridge_ip}"),
+        ("PLAY_HOST", "{host}"),
+        ("PLAY_TOTAL", "{total}"),
+    ]
+    .into_iter()
+    .filter(|(key, _)| peers || *key != "PLAY_PEERS")
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect()
+}
+
+// contexts builds template context for every instance on every host.
+pub(crate) fn contexts(network: &[core::Data]) -> Result<BTreeMap<usize, Context>> {
+    let peers: Vec<Ipv4Addr> = {
+        let mut peers: Vec<_> = network
+            .iter()
+            .flat_map(|data| data.veth.iter())
+            .map(|(index, veth)| (*index, veth.addr.ip4()))
+            .collect();
+        peers.sort();
+        peers.into_iter().map(|(_, ip)| ip).collect()
+    };
+    let mut contexts = BTreeMap::new();
+    for (host, data) in network.iter().enumerate() {
+        for (index, veth) in data.veth.iter() {
+            let bridge = data
+                .bridges
+                .get(&veth.bridge)
+                .with_context(|| format!("no bridge for instance {}", index))?;
+            contexts.insert(
+                *index,
+                Context {
+                    index: *index,
+                    ip: veth.addr.ip4(),
+                    peers: peers.clone(),
+                    bridge_ip: bridge.addr.ip4(),
+                    host: host + 1,
+                    total: peers.len(),
+                },
+            );
+        }
+    }
+    Ok(contexts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_context() -> Context {
+        Context {
+            index: 1,
+            ip: "10.0.0.3".parse().unwrap(),
+            peers: vec!["10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap()],
+            bridge_ip: "10.0.0.1".parse().unwrap(),
+            host: 2,
+            total: 2,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let ctx = test_context();
+        assert_eq!(
+            ctx.render("node --id {index}/{total} --host {host} --gw {bridge_ip}"),
+            "node --id 1/2 --host 2 --gw 10.0.0.1"
+        );
+        assert_eq!(
+            ctx.render("node --listen {ip}:7000 --peers {peers:7000}"),
+            "node --listen 10.0.0.3:7000 --peers 10.0.0.2:7000,10.0.0.3:7000"
+        );
+        assert_eq!(ctx.render("{peers}"), "10.0.0.2,10.0.0.3");
+    }
+
+    #[test]
+    fn test_render_unknown() {
+        let ctx = test_context();
+        assert_eq!(
+            ctx.render("awk '{print $1}' {ip:port} ${HOME} {index:1} {"),
+            "awk '{print $1}' {ip:port} ${HOME} {index:1} {"
+        );
+        assert_eq!(ctx.render("{{index}}"), "{1}");
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use crossbeam::{
+    channel::{Receiver, Sender, TrySendError},
+    select,
+};
+use regex::{Regex, RegexSet};
+use serde::Serialize;
+
+use crate::{fault, health, supervisor};
+
+// how many matched lines may wait for the trigger task. lines matched while the queue is full
+// are dropped, so that output readers are not blocked and a flood of matches doesn't exhaust memory
+const MATCHED_QUEUE: usize = 1024;
+// dropped lines are reported once per this number of lines
+const DROPPED_REPORT: usize = 1000;
+
+// Trigger executes the action when a line of output of any instance matches the regex.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    action: Action,
+    regex: Regex,
+}
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    // end the run with an error
+    Fail,
+    // write the matched line and status of all instances to <work_dir>/<prefix>.dump.<namespace>.json
+    Dump,
+    // apply the fault once for its duration
+    Fault(fault::Fault),
+}
+
+impl Trigger {
+    // parse <action> on <regex>
+    // EXAMPLES:
+    // fail on panic
+    // dump on data race
+    // fault kill duration 10s on became leader
+    // fault pause target random 2 duration 5s on invariant violated
+    pub fn parse(s: &str) -> Result<Self> {
+        let (action, regex) = s.split_once(" on ").with_context(|| {
+            format!(
+                "trigger must be in the form of <action> on <regex>, got {}",
+                s
+            )
+        })?;
+        let action = match action.trim().split_once(char::is_whitespace) {
+            Some(("fault", fault)) => Action::Fault(fault::Fault::parse_triggered(fault)?),
+            None if action.trim() == "fail" => Action::Fail,
+            None if action.trim() == "dump" => Action::Dump,
+            _ => anyhow::bail!(
+                "unknown trigger action {}. expected one of fail, dump, fault",
+                action
+            ),
+        };
+        Ok(Self {
+            action,
+            regex: Regex::new(regex.trim()).context("invalid trigger regex")?,
+        })
+    }
+}
+
+// Matcher is used by output readers of every instance to find lines that match any trigger.
+#[derive(Debug, Clone)]
+pub(crate) struct Matcher {
+    set: RegexSet,
+    sender: Sender<Matched>,
+    // matched lines that were dropped because the queue was full
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Matcher {
+    // new returns matcher and receiver of the lines that it matched
+    pub(crate) fn new(triggers: &[Trigger]) -> Result<(Self, Receiver<Matched>)> {
+        let (sender, receiver) = crossbeam::channel::bounded(MATCHED_QUEUE);
+        let matcher = Self {
+            set: RegexSet::new(triggers.iter().map(|trigger| trigger.regex.as_str()))?,
+            sender,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        };
+        Ok((matcher, receiver))
+    }
+
+    pub(crate) fn check(&self, index: usize, line: &str) {
+        for trigger in self.set.matches(line).iter() {
+            let matched = Matched {
+                trigger,
+                index,
+                line: line.to_string(),
+            };
+            if let Err(TrySendError::Full(_)) = self.sender.try_send(matched) {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % DROPPED_REPORT == 1 {
+                    tracing::warn!(
+                        "triggers are not handled fast enough, dropped {} matched lines",
+                        dropped
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Matched {
+    trigger: usize,
+    index: usize,
+    line: String,
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    timestamp: String,
+    index: usize,
+    namespace: &'a str,
+    regex: &'a str,
+    line: &'a str,
+    status: Vec<health::Status>,
+}
+
+pub(crate) struct Task {
+    prefix: String,
+    triggers: Vec<Trigger>,
+    commands: BTreeMap<usize, supervisor::CommandConfig>,
+    processes: fault::Processes,
+    // instance is dumped only on the first match of any dump trigger
+    dumped: BTreeSet<usize>,
+    // faults are not triggered again until previous application is reverted
+    faults: BTreeMap<usize, fault::Background>,
+}
+
+impl Task {
+    pub(crate) fn new(
+        prefix: String,
+        triggers: Vec<Trigger>,
+        commands: BTreeMap<usize, supervisor::CommandConfig>,
+        processes: fault::Processes,
+    ) -> Self {
+        Self {
+            prefix,
+            triggers,
+            commands,
+            processes,
+            dumped: BTreeSet::new(),
+            faults: BTreeMap::new(),
+        }
+    }
+
+    fn handle(&mut self, matched: Matched) -> Result<()> {
+        let trigger = &self.triggers[matched.trigger];
+        let command = self
+            .commands
+            .get(&matched.index)
+            .with_context(|| format!("unknown command {}", matched.index))?;
+        match &trigger.action {
+            Action::Fail => {
+                _ = self.processes.errors.send(Err(anyhow::anyhow!(
+                    "command {} matched trigger {}: {}",
+                    command.name,
+                    trigger.regex,
+                    matched.line
+                )));
+            }
+            Action::Dump => {
+                if !self.dumped.insert(matched.index) {
+                    return Ok(());
+                }
+                let path = self.dump(matched.index, command, &trigger.regex, &matched.line)?;
+                tracing::info!(
+                    "command {} matched trigger {}, dumped to {:?}",
+                    command.name,
+                    trigger.regex,
+                    path
+                );
+            }
+            Action::Fault(fault) => {
+                if let Some(previous) = self.faults.get(&matched.trigger) {
+                    if !previous.is_finished() {
+                        return Ok(());
+                    }
+                }
+                tracing::info!(
+                    "command {} matched trigger {}, applying fault",
+                    command.name,
+                    trigger.regex
+                );
+                let task = fault::Task::triggered(
+                    fault.clone(),
+                    matched.index,
+                    &self.commands,
+                    self.processes.clone(),
+                )?;
+                if let Some(previous) = self
+                    .faults
+                    .insert(matched.trigger, fault::Background::spawn(task)?)
+                {
+                    previous.stop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dump(
+        &self,
+        index: usize,
+        command: &supervisor::CommandConfig,
+        regex: &Regex,
+        line: &str,
+    ) -> Result<PathBuf> {
+        // status is not available if playground is not yet deployed
+        let status = health::read_status(&self.prefix).unwrap_or_default();
+        let dump = Dump {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            index,
+            namespace: &command.name,
+            regex: regex.as_str(),
+            line,
+            status,
+        };
+        let path = command
+            .work_dir
+            .join(format!("{}.dump.{}.json", self.prefix, command.name));
+        std::fs::write(&path, serde_json::to_vec_pretty(&dump)?)
+            .with_context(|| format!("write {:?}", path))?;
+        Ok(path)
+    }
+
+    fn stop(&mut self) {
+        for (_, fault) in std::mem::take(&mut self.faults) {
+            fault.stop();
+        }
+    }
+}
+
+pub(crate) struct Background {
+    sender: Sender<()>,
+    handler: JoinHandle<()>,
+}
+
+impl Background {
+    pub(crate) fn spawn(mut task: Task, matched: Receiver<Matched>) -> Result<Self> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let handle = spawn(move || {
+            loop {
+                select! {
+                    recv(receiver) -> _ => break,
+                    recv(matched) -> matched => match matched {
+                        Ok(matched) => {
+                            if let Err(err) = task.handle(matched) {
+                                tracing::error!("failed to execute trigger: {:?}", err);
+                            }
+                        }
+                        Err(_) => break,
+                    },
+                }
+            }
+            tracing::debug!("stopping trigger task");
+            // triggered faults are reverted the same way as scheduled faults
+            task.stop();
+        });
+        Ok(Self {
+            sender,
+            handler: handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        _ = self.sender.send(());
+        self.handler.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let trigger = Trigger::parse("fail on panic|data race").unwrap();
+        assert!(matches!(trigger.action, Action::Fail));
+        assert_eq!(trigger.regex.as_str(), "panic|data race");
+
+        let trigger = Trigger::parse("dump on invariant violated").unwrap();
+        assert!(matches!(trigger.action, Action::Dump));
+
+        let trigger = Trigger::parse("fault kill TERM duration 10s on became leader").unwrap();
+        assert!(matches!(trigger.action, Action::Fault(_)));
+        assert_eq!(trigger.regex.as_str(), "became leader");
+
+        assert!(Trigger::parse("fail").is_err());
+        assert!(Trigger::parse("restart on panic").is_err());
+        assert!(Trigger::parse("fault kill interval 1m duration 10s on panic").is_err());
+        assert!(Trigger::parse("fail on (").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    ffi::CString,
+    os::unix::fs::chown,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use nix::unistd::{getgrouplist, Gid, Group, User};
+use serde::{Deserialize, Serialize};
+
+// Credentials that command is executed with after privileges are dropped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    // supplementary groups, the same that login would set
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    // resolve looks up the user, and the group if provided. otherwise primary group of the user is used.
+    pub fn resolve(user: &str, group: Option<&str>) -> Result<Self> {
+        let entry = User::from_name(user)
+            .with_context(|| format!("lookup user {}", user))?
+            .with_context(|| format!("user {} doesn't exist", user))?;
+        let gid = match group {
+            Some(group) => {
+                Group::from_name(group)
+                    .with_context(|| format!("lookup group {}", group))?
+                    .with_context(|| format!("group {} doesn't exist", group))?
+                    .gid
+            }
+            None => entry.gid,
+        };
+        let groups = getgrouplist(&CString::new(user)?, gid)
+            .with_context(|| format!("lookup groups of user {}", user))?;
+        Ok(Self {
+            name: user.to_string(),
+            uid: entry.uid.as_raw(),
+            gid: gid.as_raw(),
+            home: entry.dir,
+            groups: groups.into_iter().map(Gid::as_raw).collect(),
+        })
+    }
+
+    // chown gives the file that was created by playground to the user.
+    pub(crate) fn chown(&self, path: &Path) -> Result<()> {
+        chown(path, Some(self.uid), Some(self.gid)).with_context(|| format!("chown {:?}", path))
+    }
+}